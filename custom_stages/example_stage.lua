@@ -1,5 +1,5 @@
 stage = {
-    apply = function ()
-        print("Custom stage test code here")
+    apply = function (ctx)
+        ctx.log.info("Custom stage test code here")
     end
 }
\ No newline at end of file
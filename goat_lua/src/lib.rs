@@ -5,6 +5,7 @@ use goat_lua_macro::lua_module;
 /// The goat lua runtime module.
 #[lua_module]
 pub fn goat(lua: &Lua) -> anyhow::Result<Table> {
+    /// Returns true if `program` can be found in `PATH`.
     pub fn program_exists(program: &str) -> bool {
         match which::which(program) {
             Ok(_) => true,
@@ -2,8 +2,97 @@
 // - Lucas Marta
 
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, Data, Fields, Item};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Attribute, Data, Expr, Fields, GenericArgument, Item, Lit, LitStr, Meta, PathArguments, ReturnType, Type};
+
+/// Collect the `///` doc comment lines of an item.
+fn doc_lines(attrs: &[Attribute]) -> Vec<String> {
+    attrs.iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta {
+            Meta::NameValue(name_value) => match &name_value.value {
+                Expr::Lit(expr) => match &expr.lit {
+                    Lit::Str(string) => Some(string.value()),
+                    _ => None
+                },
+                _ => None
+            },
+            _ => None
+        })
+        .map(|line| line.strip_prefix(' ').unwrap_or(&line).trim_end().to_string())
+        .collect()
+}
+
+/// Write doc comment lines as LuaLS `---` comments.
+fn lua_doc(lines: &[String]) -> String {
+    lines.iter().map(|line| format!("---{}\n", line)).collect()
+}
+
+/// The LuaLS type of a rust type, `any` if there is no obvious one.
+fn lua_type(ty: &Type) -> String {
+    let segment = match ty {
+        Type::Reference(reference) => return lua_type(&reference.elem),
+        Type::Path(path) => match path.path.segments.last() {
+            Some(segment) => segment,
+            None => return String::from("any")
+        },
+        _ => return String::from("any")
+    };
+    
+    let arguments: Vec<String> = match &segment.arguments {
+        PathArguments::AngleBracketed(arguments) => arguments.args.iter()
+            .filter_map(|argument| match argument {
+                GenericArgument::Type(ty) => Some(lua_type(ty)),
+                _ => None
+            })
+            .collect(),
+        _ => vec![]
+    };
+    
+    match (segment.ident.to_string().as_str(), arguments.as_slice()) {
+        ("String" | "str" | "PathBuf" | "Path", _) => String::from("string"),
+        ("bool", _) => String::from("boolean"),
+        ("i8" | "i16" | "i32" | "i64" | "u8" | "u16" | "u32" | "u64" | "usize" | "isize", _) => String::from("integer"),
+        ("f32" | "f64", _) => String::from("number"),
+        ("Vec", [item]) => format!("{}[]", item),
+        ("Option", [item]) => format!("{}?", item),
+        ("HashMap" | "BTreeMap", [key, value]) => format!("table<{}, {}>", key, value),
+        _ => String::from("any")
+    }
+}
+
+/// Options from `#[lua(...)]` attributes, see `derive_lua_types`.
+#[derive(Default)]
+struct LuaOptions {
+    skip: bool,
+    class: Option<String>,
+    rename: Option<String>,
+    ty: Option<String>
+}
+
+fn lua_options(attrs: &[Attribute]) -> syn::Result<LuaOptions> {
+    let mut options = LuaOptions::default();
+    
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("lua")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                options.skip = true;
+            } else if meta.path.is_ident("class") {
+                options.class = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("rename") {
+                options.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("ty") {
+                options.ty = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else {
+                return Err(meta.error("expected `skip`, `class`, `rename` or `ty`"));
+            }
+            
+            Ok(())
+        })?;
+    }
+    
+    Ok(options)
+}
 
 /// This procedural macro is used for extracting globals in files such as package manager 
 /// configuration files and service manager configuration files. The reason for seperating this into
@@ -57,8 +146,8 @@ pub fn derive_from_lua_file(input: TokenStream) -> TokenStream {
                     
                     if let Ok(value) = globals.get::<mlua::Value>(#field_name_str) {
                         if let Some(table) = value.as_table() {
-                            #field_name = Some(sequence_values::<String>()
-                                .collect::<Result<Ved<_>, _>>()
+                            #field_name = Some(table.sequence_values::<String>()
+                                .collect::<Result<Vec<_>, _>>()
                                 .map_err(|e| anyhow::anyhow!("{}", e))?);
                         }
                     }
@@ -79,11 +168,8 @@ pub fn derive_from_lua_file(input: TokenStream) -> TokenStream {
         } else {
             if is_optional {
                 quote! {
-                    let #field_name = if let Ok(value) = globals.get(#field_name_str) {
-                        Some(value)
-                    } else {
-                        None
-                    }
+                    // A missing global is nil, which converts to `None`.
+                    let #field_name = globals.get::<Option<_>>(#field_name_str).map_err(|e| anyhow::anyhow!("{}", e))?;
                 }
             } else {
                 quote! {
@@ -140,15 +226,118 @@ pub fn derive_from_lua_file(input: TokenStream) -> TokenStream {
     })
 }
 
+/// Generate LuaLS type definitions (`---@meta` files) for a struct from its fields and their doc
+/// comments, see `crate::lua_types::LuaTypes` in goat.
+/// 
+/// By default every field is written as a global, which fits configuration files like config.lua
+/// or the package manager configurations. `#[lua(class = "goat.Name")]` on the struct writes a
+/// `---@class` with a `---@field` per field instead, for tables inside those files.
+/// 
+/// Fields accept `#[lua(skip)]`, `#[lua(rename = "name")]` and `#[lua(ty = "string|string[]")]`
+/// for when the rust field doesn't match what is written in lua.
+#[proc_macro_derive(LuaTypes, attributes(lua))]
+pub fn derive_lua_types(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as syn::DeriveInput);
+    let name = &input.ident;
+    
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => panic!("LuaTypes can only be derived for structs with named fields.")
+        },
+        _ => panic!("LuaTypes can only be derived for structs.")
+    };
+    
+    let struct_options = match lua_options(&input.attrs) {
+        Ok(options) => options,
+        Err(e) => return e.to_compile_error().into()
+    };
+    
+    let mut definitions = String::new();
+    
+    if let Some(class) = &struct_options.class {
+        definitions.push_str(&lua_doc(&doc_lines(&input.attrs)));
+        definitions.push_str(&format!("---@class {}\n", class));
+    }
+    
+    for field in fields {
+        let options = match lua_options(&field.attrs) {
+            Ok(options) => options,
+            Err(e) => return e.to_compile_error().into()
+        };
+        
+        if options.skip {
+            continue;
+        }
+        
+        let field_name = options.rename.unwrap_or_else(|| field.ident.as_ref().map(|ident| ident.to_string()).unwrap_or_default());
+        let field_type = options.ty.unwrap_or_else(|| lua_type(&field.ty));
+        let docs = doc_lines(&field.attrs);
+        
+        if struct_options.class.is_some() {
+            let description = docs.join(" ");
+            definitions.push_str(&format!("---@field {} {}", field_name, field_type));
+            if !description.trim().is_empty() {
+                definitions.push_str(&format!(" {}", description.trim()));
+            }
+            definitions.push('\n');
+        } else {
+            definitions.push_str(&format!("\n{}---@type {}\n{} = nil\n", lua_doc(&docs), field_type, field_name));
+        }
+    }
+    
+    TokenStream::from(quote! {
+        impl crate::lua_types::LuaTypes for #name {
+            fn lua_types() -> &'static str {
+                #definitions
+            }
+        }
+    })
+}
+
 /// Create a lua table for use in `Lua::new().globals().set(...)`.
 /// 
 /// This makes it super easy for me to create my lua runtime for goat.
+/// 
+/// Alongside the module a `<name>_lua_types` function is generated, returning LuaLS type
+/// definitions of the module built from the function signatures and their doc comments.
 #[proc_macro_attribute]
 pub fn lua_module(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let input = parse_macro_input!(item as syn::ItemFn);
     
+    let attrs = &input.attrs;
     let vis = &input.vis;
     let sig = &input.sig;
+    let module_name = sig.ident.to_string();
+    let types_function = format_ident!("{}_lua_types", sig.ident);
+    
+    let mut definitions = format!("{}---@class {}\n{} = {{}}\n", lua_doc(&doc_lines(attrs)), module_name, module_name);
+    
+    for statement in &input.block.stmts {
+        if let syn::Stmt::Item(Item::Fn(inner_fn)) = statement {
+            let mut parameters = vec![];
+            definitions.push('\n');
+            definitions.push_str(&lua_doc(&doc_lines(&inner_fn.attrs)));
+            
+            for input in &inner_fn.sig.inputs {
+                if let syn::FnArg::Typed(argument) = input {
+                    let parameter = match argument.pat.as_ref() {
+                        syn::Pat::Ident(ident) => ident.ident.to_string(),
+                        _ => format!("arg{}", parameters.len() + 1)
+                    };
+                    
+                    definitions.push_str(&format!("---@param {} {}\n", parameter, lua_type(&argument.ty)));
+                    parameters.push(parameter);
+                }
+            }
+            
+            if let ReturnType::Type(_, ty) = &inner_fn.sig.output {
+                definitions.push_str(&format!("---@return {}\n", lua_type(ty)));
+            }
+            
+            definitions.push_str(&format!("function {}.{}({}) end\n", module_name, inner_fn.sig.ident, parameters.join(", ")));
+        }
+    }
     
     let functions = input.block.stmts.iter().map(|statement| {
         if let syn::Stmt::Item(Item::Fn(inner_fn)) = statement {
@@ -169,10 +358,16 @@ pub fn lua_module(_attr: TokenStream, item: TokenStream) -> TokenStream {
     });
     
     TokenStream::from(quote! {
+        #(#attrs)*
         #vis #sig {
             let module_table = lua.create_table().map_err(|e| anyhow::anyhow!("{}", e))?;
             #(#functions);*
             Ok(module_table)
         }
+        
+        /// LuaLS type definitions of the module, see `lua_module`.
+        #vis fn #types_function() -> &'static str {
+            #definitions
+        }
     })
 }
\ No newline at end of file
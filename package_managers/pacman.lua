@@ -6,11 +6,65 @@ if goat.program_exists("paru") then
     binary_name = "paru"
 end
 
-install_command = binary_name .. " -S --noconfirm {}"
-remove_command = binary_name .. " -Rns --noconfirm {}"
-full_system_update_command = binary_name .. " -Syu --noconfirm"
+-- AUR helpers refuse to build packages as root, so only packages from the pacman repositories are
+-- installed as root. Everything else is built by the AUR helper as the configured build user, which
+-- needs to be allowed to run pacman through sudo.
+install_command = "pacman -S --noconfirm {}"
+list_repository_packages_command = "pacman -Slq"
+if binary_name == "paru" then
+    unprivileged_install_command = "paru -S --noconfirm {}"
+end
+remove_command = "pacman -Rns --noconfirm {}"
+-- goat runs as root, which AUR helpers refuse to.
+full_system_update_command = "pacman -Syu --noconfirm"
 list_explicit_packages_command = binary_name .. " -Qe | cut -d ' ' -f1"
 list_all_packages_command = binary_name .. " -Q | cut -d ' ' -f1"
+info_command = binary_name .. " -Si {}"
+-- These exit with 1 when nothing matches, which isn't an error for goat.
+search_command = binary_name .. " -Ssq {} || true"
+list_group_members_command = "pacman -Sgq {} || true"
+mark_as_dependency_command = "pacman -D --asdeps {}"
+mark_as_explicit_command = "pacman -D --asexplicit {}"
+list_orphans_command = "pacman -Qdtq || true"
+list_versions_command = "pacman -Q"
+-- Pinned versions are installed from the package cache, pacman can't download old versions. A pin
+-- without a package release matches every cached release of that version, the newest one is used.
+install_version_command = "package=$(ls -1 /var/cache/pacman/pkg/{name}-{version}-*.pkg.tar.zst 2>/dev/null | sort -V | tail -n1)"
+    .. " && [ -n \"$package\" ] && pacman -U --noconfirm \"$package\""
+-- pacman has no hold command, add the package to IgnorePkg in pacman.conf once. Names are compared
+-- as plain strings, `+` is valid in package names. The first IgnorePkg line is extended, the
+-- commented out default line is used if there is none, otherwise one is added to [options].
+hold_command = "awk -F '[ =]+' '/^IgnorePkg/ { for (i = 2; i <= NF; i++) if ($i == \"{name}\") found = 1 } END { exit !found }' /etc/pacman.conf"
+    .. " || if grep -q '^IgnorePkg' /etc/pacman.conf; then sed -i -E '0,/^IgnorePkg/s/^(IgnorePkg.*)$/\\1 {name}/' /etc/pacman.conf;"
+    .. " elif grep -Eq '^#IgnorePkg *=' /etc/pacman.conf; then sed -i -E '0,/^#IgnorePkg/s/^#IgnorePkg *=(.*)$/IgnorePkg =\\1 {name}/' /etc/pacman.conf;"
+    .. " else sed -i '/^\\[options\\]/a IgnorePkg = {name}' /etc/pacman.conf; fi"
+-- Releasing a hold drops the name from every IgnorePkg line, a line left empty is commented out.
+unhold_command = "tmp=$(mktemp) && awk -F '[ =]+' '/^IgnorePkg/ { line = \"\"; held = 0;"
+    .. " for (i = 2; i <= NF; i++) if ($i == \"{name}\") held = 1; else if ($i != \"\") line = line \" \" $i;"
+    .. " if (held) { print (line == \"\" ? \"#IgnorePkg =\" : \"IgnorePkg =\" line); next } } { print }' /etc/pacman.conf > \"$tmp\""
+    .. " && cat \"$tmp\" > /etc/pacman.conf && rm \"$tmp\""
+-- Repositories are kept in their own file, included at the end of pacman.conf. pacman prefers
+-- repositories in the order they appear, so the distribution's repositories always come first and
+-- `priority` only orders the configured repositories among each other.
+repositories_file = "/etc/pacman.d/goat-repositories.conf"
+repository_template = "[{name}]\nServer = {url}\n"
+import_key_command = "pacman-key --recv-keys {key} && pacman-key --lsign-key {key}"
+-- Refreshing the databases without upgrading would be a partial upgrade, which Arch doesn't
+-- support, so this only includes the repositories file. The databases are refreshed by the next
+-- system upgrade, or right away with `full_upgrade_on_repository_change = true` in the configuration.
+refresh_repositories_command = "grep -qxF 'Include = " .. repositories_file .. "' /etc/pacman.conf"
+    .. " || echo 'Include = " .. repositories_file .. "' >> /etc/pacman.conf"
+-- `goat fetch` downloads into its own database so every dependency is fetched, not just the ones
+-- missing on this machine. Offline installs take every package in the cache (there is no `{}`, so
+-- goat runs it once), the dependencies are installed as such and the configured packages get
+-- marked as explicit afterward.
+download_command = "mkdir -p {cache}/db && pacman -Syw --noconfirm --cachedir {cache} --dbpath {cache}/db {}"
+-- Pinned versions can't be downloaded either, they're copied from pacman's own package cache.
+download_version_command = "package=$(ls -1 /var/cache/pacman/pkg/{name}-{version}-*.pkg.tar.zst 2>/dev/null | sort -V | tail -n1)"
+    .. " && [ -n \"$package\" ] && cp \"$package\" {cache}/"
+offline_install_command = "pacman -U --noconfirm --needed --asdeps {cache}/*.pkg.tar.zst"
+list_modified_files_command = "pacman -Qii | awk '/^MODIFIED/ {print $2}'"
+list_owned_files_command = "pacman -Qlq"
 
 core_packages = {
     "base"
@@ -1,3 +1,4 @@
 -- systemd has an interesting specificaation.
 binary_name = "systemctl"
-hostname_reload_command = "hostnamectl set-hostname \"$(cat /etc/hostname)\""
\ No newline at end of file
+hostname_reload_command = "hostnamectl set-hostname \"$(cat /etc/hostname)\""
+list_enabled_services_command = "systemctl list-unit-files --state=enabled --type=service --no-legend --no-pager | cut -d ' ' -f1"
\ No newline at end of file
@@ -2,7 +2,7 @@ use std::{fs, path::PathBuf};
 
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct Cache {
     /// The name of the configuration file
     /// used last by `goat` should be just
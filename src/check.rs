@@ -0,0 +1,32 @@
+use crate::goat::Goat;
+use crate::stage::Packages;
+// check.rs
+//
+// All logic related to the `check` subcommand should be placed here. Anything that can be
+// validated without modifying the system belongs in here.
+
+impl Goat {
+    /// Validate the loaded configuration against the system, printing every problem found.
+    ///
+    /// Returns true if no problems were found.
+    pub fn check(&self) -> anyhow::Result<bool> {
+        let mut problems = 0;
+
+        if let Some(packages) = Packages::configured(self)? {
+            let packages: Vec<&str> = packages.iter().map(|package| package.as_str()).collect();
+
+            for package in self.package_manager.unknown_packages(&packages)? {
+                println!("Unknown package {}", package);
+                problems += 1;
+            }
+        }
+
+        if problems == 0 {
+            println!("Configuration is valid.");
+        } else {
+            println!("Found {} problem(s).", problems);
+        }
+
+        Ok(problems == 0)
+    }
+}
@@ -0,0 +1,208 @@
+use std::ffi::CString;
+use std::io::IsTerminal;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use anyhow::anyhow;
+use nix::unistd::{getgrouplist, setgid, setgroups, setuid, User};
+use crate::events;
+
+/// `PATH` for commands run as another user, ours might point into root's directories.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/bin:/bin";
+
+/// Environment variables passed on to commands run as another user, everything else (`SUDO_*`,
+/// root's `PATH`, ...) is cleared.
+const KEPT_VARIABLES: [&str; 3] = ["TERM", "LANG", "LC_ALL"];
+
+/// Run a shell command from a configuration template and return every non empty line of its
+/// output.
+/// 
+/// This is used for all of the `list_*_command` templates found in package manager and service
+/// manager configurations. A command that fails is an error, an empty list has to come with a
+/// successful exit status.
+pub fn output_lines(command: &str) -> anyhow::Result<Vec<String>> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|e| anyhow!("Failed to execute \"{}\": {}", command, e))?;
+    
+    if !output.status.success() {
+        return Err(anyhow!("\"{}\" failed with output: \n\n{}", command, String::from_utf8_lossy(&output.stderr)))
+    }
+    
+    // Theoretically this should be safe unless the package
+    // manager's output is something weird like non UTF-8.
+    let stdout = String::from_utf8(output.stdout)?;
+    
+    Ok(stdout
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_owned())
+        .collect())
+}
+
+/// Quote `argument` so `sh` passes it through as a single argument.
+pub fn shell_quote(argument: &str) -> String {
+    format!("'{}'", argument.replace('\'', "'\\''"))
+}
+
+/// Run a shell command and return whether it exited successfully. Output is discarded.
+pub fn succeeds(command: &str) -> anyhow::Result<bool> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|e| anyhow!("Failed to execute \"{}\": {}", command, e))?;
+    
+    Ok(output.status.success())
+}
+
+/// Run a shell command, returning an error with its output if it fails.
+pub fn run(command: &str) -> anyhow::Result<()> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|e| anyhow!("Failed to execute \"{}\": {}", command, e))?;
+    
+    if !output.status.success() {
+        return Err(anyhow!("\"{}\" failed with output: \n\n{}", command, String::from_utf8(output.stderr)?))
+    }
+    
+    Ok(())
+}
+
+/// Run a shell command and return its exit status and captured output without checking whether
+/// it succeeded.
+pub fn output(command: &str) -> anyhow::Result<Output> {
+    Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|e| anyhow!("Failed to execute \"{}\": {}", command, e))
+}
+
+/// Substitute a list of arguments into a command template's `{}`, each one quoted.
+pub fn fill_template(template: &str, arguments: &[&str]) -> String {
+    let arguments: Vec<String> = arguments.iter().map(|argument| shell_quote(argument)).collect();
+    template.replace("{}", &arguments.join(" "))
+}
+
+/// Substitute named placeholders like `{name}` in a command template.
+/// 
+/// Values aren't quoted so templates can use them inside globs or quoted strings, anything that
+/// isn't safe to pass to `sh` unquoted is rejected instead.
+pub fn fill_placeholders(template: &str, placeholders: &[(&str, &str)]) -> anyhow::Result<String> {
+    let mut command = template.to_owned();
+    
+    for (placeholder, value) in placeholders {
+        if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphanumeric() || "@._+:~-".contains(c)) {
+            return Err(anyhow!("\"{}\" can't be used as a {}", value, placeholder));
+        }
+        
+        command = command.replace(&format!("{{{}}}", placeholder), value);
+    }
+    
+    Ok(command)
+}
+
+/// Same as `fill_placeholders` but every value is quoted, for values like URLs that can't be
+/// validated.
+pub fn fill_quoted_placeholders(template: &str, placeholders: &[(&str, &str)]) -> String {
+    let mut command = template.to_owned();
+    
+    for (placeholder, value) in placeholders {
+        command = command.replace(&format!("{{{}}}", placeholder), &shell_quote(value));
+    }
+    
+    command
+}
+
+/// Same as `run` but the command runs as `user` instead of root, for tools like AUR helpers that
+/// refuse to build packages as root.
+/// 
+/// Output isn't captured since these tools might still ask for a password. When goat isn't run
+/// interactively stdin is closed so such a prompt fails instead of hanging.
+pub fn run_as(command: &str, user: &str) -> anyhow::Result<()> {
+    let user = User::from_name(user)?.ok_or_else(|| anyhow!("User \"{}\" doesn't exist", user))?;
+    let (uid, gid) = (user.uid, user.gid);
+    
+    // Supplementary groups like wheel are needed by tools that call sudo themselves.
+    let groups = getgrouplist(&CString::new(user.name.as_str())?, gid)
+        .map_err(|e| anyhow!("Failed to look up the groups of \"{}\": {}", user.name, e))?;
+    
+    let mut child = Command::new("sh");
+    child
+        .arg("-c")
+        .arg(command)
+        .env_clear()
+        .envs(KEPT_VARIABLES.iter().filter_map(|name| std::env::var_os(name).map(|value| (name, value))))
+        .env("PATH", DEFAULT_PATH)
+        .env("HOME", &user.dir)
+        .env("USER", &user.name)
+        .env("LOGNAME", &user.name)
+        // Our working directory might not be accessible to the user.
+        .current_dir(if user.dir.is_dir() { user.dir.as_path() } else { Path::new("/") });
+    
+    if !std::io::stdin().is_terminal() {
+        child.stdin(Stdio::null());
+    }
+    
+    // Build output would end up in the middle of the event stream.
+    if events::json_output() {
+        child.stdout(std::io::stderr());
+    }
+    
+    // The groups have to be dropped before the user, afterward we aren't allowed to anymore.
+    // Only async-signal-safe calls are allowed between fork and exec so the user and their groups
+    // are looked up beforehand.
+    unsafe {
+        child.pre_exec(move || {
+            setgroups(&groups)?;
+            setgid(gid)?;
+            setuid(uid)?;
+            Ok(())
+        });
+    }
+    
+    let status = child
+        .status()
+        .map_err(|e| anyhow!("Failed to execute \"{}\" as \"{}\": {}", command, user.name, e))?;
+    
+    if !status.success() {
+        return Err(anyhow!("\"{}\" failed when run as \"{}\"", command, user.name))
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_filled_in_everywhere() {
+        let command = fill_placeholders("pacman -U /cache/{name}-{version}-*.pkg.tar.zst && echo {name}", &[
+            ("name", "gtk+3"),
+            ("version", "1:3.24.43-1")
+        ]).unwrap();
+
+        assert_eq!(command, "pacman -U /cache/gtk+3-1:3.24.43-1-*.pkg.tar.zst && echo gtk+3");
+    }
+
+    #[test]
+    fn unsafe_placeholder_values_are_rejected() {
+        for value in ["", "a b", "a;rm -rf /", "$(id)", "`id`", "a'b", "a\"b", "a/../b", "*", "a|b", "a\nb"] {
+            let error = fill_placeholders("pacman -S {name}", &[("name", value)]).unwrap_err();
+            assert_eq!(error.to_string(), format!("\"{}\" can't be used as a name", value));
+        }
+    }
+
+    #[test]
+    fn templates_quote_their_arguments() {
+        assert_eq!(fill_template("pacman -S {}", &["vim", "it's"]), "pacman -S 'vim' 'it'\\''s'");
+        assert_eq!(fill_quoted_placeholders("curl {url}", &[("url", "https://a/b?c=d&e")]), "curl 'https://a/b?c=d&e'");
+    }
+}
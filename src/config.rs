@@ -1,13 +1,64 @@
-use std::path::Path;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use anyhow::anyhow;
 use mlua::Value;
 use regex::Regex;
+use serde::Serialize;
 use goat_lua::GoatLua;
+use goat_lua_macro::LuaTypes;
+use crate::events;
+use crate::hooks::Hooks;
+use crate::merge::{Merge, Sources};
+use crate::package_manager::InstallStrategy;
+
+/// Packages per install command when `install_strategy = "batches"` has no `install_batch_size`.
+const DEFAULT_INSTALL_BATCH_SIZE: usize = 25;
+
+/// A package from the `packages` table written as a table instead of a name:
+/// 
+/// `{ name = "linux-zen", version = "6.9.1", hold = true }`
+#[derive(Serialize, LuaTypes)]
+#[lua(class = "goat.PackagePin")]
+pub struct PackagePin {
+    pub name: String,
+    
+    /// The version the package has to be installed at.
+    pub version: Option<String>,
+    
+    /// Keep the package manager from upgrading the package.
+    #[lua(ty = "boolean?")]
+    pub hold: bool
+}
+
+/// An extra package repository from the `repositories` table:
+/// 
+/// `{ name = "chaotic-aur", url = "https://cdn-mirror.chaotic.cx/$repo/$arch", key = "3056513887B78AEB", priority = 10 }`
+#[derive(Serialize, LuaTypes)]
+#[lua(class = "goat.Repository")]
+pub struct Repository {
+    pub name: String,
+    
+    /// Mirrors of the repository, `url` can be a single string or a list of them.
+    #[lua(rename = "url", ty = "string|string[]")]
+    pub urls: Vec<String>,
+    
+    /// Key the repository is signed with, imported when the repository is added.
+    pub key: Option<String>,
+    
+    /// Repositories with a lower priority are preferred over other configured repositories.
+    /// Defaults to 99.
+    /// 
+    /// This only orders the configured repositories among each other. With pacman they are all
+    /// included at the end of pacman.conf, so the distribution's repositories always win.
+    #[lua(ty = "integer?")]
+    pub priority: i64
+}
 
 /// `goat`'s configuration file specification.
 /// 
 /// Here lies every configuration option
 /// for the goat system.
+#[derive(Serialize, LuaTypes)]
 pub struct Config {
     /// The system's hostname. `systemd` systems define this as 
     /// `/etc/hostname` and provides `hostnamectl`. For this
@@ -19,7 +70,57 @@ pub struct Config {
     /// The list of packages the user explicitly wants installed.
     /// Dependency packages will be pulled in implicitly by their package
     /// manager.
+    #[lua(ty = "(string|goat.PackagePin)[]")]
     pub packages: Option<Vec<String>>,
+    
+    /// Packages from `packages` with a pinned version or hold. Their names are in `packages` as
+    /// well.
+    #[lua(skip)]
+    pub package_pins: Vec<PackagePin>,
+    
+    /// Extra package repositories, sorted by priority.
+    #[lua(ty = "goat.Repository[]")]
+    pub repositories: Vec<Repository>,
+    
+    /// Unprivileged user packages that can't be built as root (AUR packages) are built as.
+    /// Defaults to the user that ran `sudo goat`.
+    pub build_user: Option<String>,
+    
+    /// The file every configured item came from, see `Sources`.
+    #[lua(skip)]
+    pub sources: Sources,
+    
+    /// The roles that were merged into the configuration, from `profiles` and `--profile`.
+    pub profiles: Vec<String>,
+    
+    /// How packages are split into install commands, see `InstallStrategy`.
+    /// 
+    /// Set with `install_strategy = "single" | "batches" | "fallback"` and `install_batch_size`.
+    #[lua(ty = "\"single\"|\"batches\"|\"fallback\"")]
+    pub install_strategy: InstallStrategy,
+    
+    /// Mark packages removed from `packages` as dependencies instead of uninstalling them, they
+    /// are only uninstalled once no other package depends on them.
+    pub demote_unneeded_packages: bool,
+    
+    /// Upgrade the whole system when `repositories` change, in the middle of the sync. Package
+    /// managers like pacman only see new repositories after an upgrade.
+    pub full_upgrade_on_repository_change: bool,
+    
+    /// Count unknown packages as skipped, which fails the sync. They are only warned about
+    /// otherwise.
+    pub strict_packages: bool,
+    
+    /// Commands and lua functions run around the sync and its stages, see `Hooks`.
+    #[serde(skip)]
+    #[lua(ty = "goat.Hooks?")]
+    pub hooks: Hooks,
+    
+    /// The lua state the configuration was evaluated in. Lua functions from the configuration
+    /// (the hooks) can only be called while it is alive.
+    #[serde(skip)]
+    #[lua(skip)]
+    pub runtime: Option<GoatLua>,
 }
 
 impl Default for Config {
@@ -27,15 +128,64 @@ impl Default for Config {
         Config {
             hostname: String::from("goatOS"),
             packages: None,
+            package_pins: vec![],
+            repositories: vec![],
+            build_user: std::env::var("SUDO_USER").ok(),
+            install_strategy: InstallStrategy::Single,
+            sources: Sources::new(),
+            profiles: vec![],
+            demote_unneeded_packages: false,
+            full_upgrade_on_repository_change: false,
+            strict_packages: false,
+            hooks: Hooks::default(),
+            runtime: None,
         }
     }
 }
 
+/// The hostname of the running machine, used to pick the host overlay.
+pub fn current_hostname() -> Option<String> {
+    std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|hostname| hostname.trim().to_owned())
+        .filter(|hostname| !hostname.is_empty())
+}
+
 impl Config {
-    /// Create a `Config` instance from a file path.
-    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+    /// Create a `Config` instance from a file path, with the overlay of the running machine
+    /// applied and `profiles` enabled on top of the configured ones.
+    pub fn from_file(path: &Path, profiles: &[String]) -> anyhow::Result<Self> {
+        Self::for_host(path, current_hostname().as_deref(), profiles)
+    }
+    
+    /// Create a `Config` instance from a file path as it would be evaluated on `host`.
+    pub fn for_host(path: &Path, host: Option<&str>, profiles: &[String]) -> anyhow::Result<Self> {
         let lua = GoatLua::create()?;
+        // Hooks keep using this runtime during the sync.
+        events::redirect_print(&lua.lua)?;
+        let (table, sources) = Self::evaluate(&lua, path, host, profiles)?;
+        
+        let mut config = Self::from_table(&table)?;
+        config.sources = sources;
+        config.runtime = Some(lua);
         
+        Ok(config)
+    }
+    
+    /// Evaluate a configuration file and return a table of every global it set, along with the file
+    /// each item came from.
+    /// 
+    /// Modules listed in `imports` are merged in first, conflicting values between modules are an
+    /// error. If `host` is given and `hosts/<host>.lua` exists next to the configuration file, it is
+    /// evaluated afterward and merged on top (see `merge.rs`). Modules and the overlay can read
+    /// everything the configuration file set.
+    /// 
+    /// Last, the `roles` named in `profiles` and in `extra_profiles` are merged in. The roles table
+    /// itself is left out of the result and `profiles` lists every role that was merged.
+    pub fn evaluate(lua: &GoatLua,
+                    path: &Path,
+                    host: Option<&str>,
+                    extra_profiles: &[String]) -> anyhow::Result<(mlua::Table, Sources)> {
         if !path.exists() {
             return Err(anyhow!("Config file: \"{}\" does not exist", path.display()))
         }
@@ -43,26 +193,182 @@ impl Config {
         let config_script = std::fs::read_to_string(path)?;
 
         let globals = lua.lua.globals();
-
-        let package: mlua::Table = globals.get("package").map_err(|e| anyhow!("{}", e))?;
+        
+        let directory = path.parent().ok_or_else(|| anyhow!("Invalid path"))?;
+        Self::extend_package_path(lua, directory)?;
+        
+        // Anything that is already a global belongs to lua or goat, not the configuration.
+        let runtime_globals: HashSet<String> = globals.pairs::<String, Value>()
+            .filter_map(|pair| pair.ok().map(|(key, _)| key))
+            .collect();
+        
+        // The mlua library doesn't seem to be friendly with anyhow so we still need to use map_err 
+        // on each Result returning function from them.
+        lua.lua.load(&config_script).set_name(path.to_string_lossy()).exec().map_err(|e| anyhow!("Failed to interpret configuration file: \n{}\n", e))?;
+        
+        let base = lua.lua.create_table().map_err(|e| anyhow!("{}", e))?;
+        for pair in globals.pairs::<String, Value>() {
+            let (key, value) = pair.map_err(|e| anyhow!("{}", e))?;
+            if !runtime_globals.contains(&key) {
+                base.set(key, value).map_err(|e| anyhow!("{}", e))?;
+            }
+        }
+        
+        let config = lua.lua.create_table().map_err(|e| anyhow!("{}", e))?;
+        let mut merge = Merge::new(&lua.lua);
+        
+        // Modules go in first so the configuration file itself can override them.
+        Self::merge_imports(lua, &mut merge, &config, &base, directory, &mut HashSet::new())?;
+        base.raw_remove("imports").map_err(|e| anyhow!("{}", e))?;
+        merge.merge(&config, &base, &path.file_name().unwrap_or_default().to_string_lossy(), false)?;
+        
+        if let Some(host) = host {
+            let overlay_path = directory.join("hosts").join(format!("{}.lua", host));
+            
+            if overlay_path.exists() {
+                log::debug!("Applying host overlay \"{}\"", overlay_path.display());
+                
+                let overlay = Self::evaluate_module(lua, &overlay_path)?;
+                merge.merge(&config, &overlay, &format!("hosts/{}.lua", host), false)?;
+            }
+        }
+        
+        Self::merge_profiles(lua, &mut merge, &config, extra_profiles)?;
+        
+        Ok((config, merge.sources))
+    }
+    
+    /// Merge the roles named in the `profiles` of `config` and in `extra_profiles` into `config`.
+    /// 
+    /// Roles are merged strictly, a role can add to what the configuration sets but not change it.
+    fn merge_profiles(lua: &GoatLua,
+                      merge: &mut Merge,
+                      config: &mlua::Table,
+                      extra_profiles: &[String]) -> anyhow::Result<()> {
+        let roles = config.get::<Option<mlua::Table>>("roles").map_err(|e| anyhow!("{}", e))?;
+        
+        let mut profiles: Vec<String> = match config.get::<Option<mlua::Table>>("profiles").map_err(|e| anyhow!("{}", e))? {
+            Some(profiles) => profiles.sequence_values::<String>()
+                .collect::<Result<_, _>>()
+                .map_err(|e| anyhow!("Invalid entry in profiles: {}", e))?,
+            None => vec![]
+        };
+        for profile in extra_profiles {
+            if !profiles.contains(profile) {
+                profiles.push(profile.clone());
+            }
+        }
+        
+        for profile in &profiles {
+            let role = match &roles {
+                Some(roles) => roles.get::<Option<mlua::Table>>(profile.as_str()).map_err(|e| anyhow!("{}", e))?,
+                None => None
+            };
+            let Some(role) = role else {
+                let mut available: Vec<String> = match &roles {
+                    Some(roles) => roles.pairs::<String, Value>()
+                        .filter_map(|pair| pair.ok().map(|(name, _)| name))
+                        .collect(),
+                    None => vec![]
+                };
+                available.sort();
+                
+                return Err(anyhow!("Unknown profile \"{}\", the roles table defines: {}",
+                    profile,
+                    if available.is_empty() { String::from("nothing") } else { available.join(", ") }));
+            };
+            
+            log::debug!("Enabling profile \"{}\"", profile);
+            merge.merge(config, &role, &format!("profile:{}", profile), true)?;
+        }
+        
+        // Roles that weren't enabled don't belong to the configuration.
+        config.raw_remove("roles").map_err(|e| anyhow!("{}", e))?;
+        merge.sources.retain(|item, _| !item.starts_with("roles."));
+        
+        if !profiles.is_empty() {
+            config.raw_set("profiles", lua.lua.create_sequence_from(profiles).map_err(|e| anyhow!("{}", e))?)
+                .map_err(|e| anyhow!("{}", e))?;
+        }
+        
+        Ok(())
+    }
+    
+    /// Let `require` find lua files in `directory`.
+    pub fn extend_package_path(lua: &GoatLua, directory: &Path) -> anyhow::Result<()> {
+        let package: mlua::Table = lua.lua.globals().get("package").map_err(|e| anyhow!("{}", e))?;
         let old_path: String = package.get("path").map_err(|e| anyhow!("{}", e))?;
-        let new_path = path
-            .parent()
-            .ok_or_else(|| anyhow!("Invalid path"))?
-            .to_string_lossy()
-            .to_string();
         
         package.set(
             "path", 
-            format!("{};{}/?.lua", old_path, new_path)
-        ).map_err(|e| anyhow!("{}", e))?;
+            format!("{};{}/?.lua", old_path, directory.to_string_lossy())
+        ).map_err(|e| anyhow!("{}", e))
+    }
+    
+    /// Evaluate a module or overlay file in its own environment and return the environment.
+    /// 
+    /// Globals the file sets end up in the environment, reads fall through to the globals of the
+    /// configuration file and the runtime.
+    fn evaluate_module(lua: &GoatLua, path: &Path) -> anyhow::Result<mlua::Table> {
+        let environment = lua.lua.create_table().map_err(|e| anyhow!("{}", e))?;
+        let metatable = lua.lua.create_table().map_err(|e| anyhow!("{}", e))?;
+        metatable.set("__index", lua.lua.globals()).map_err(|e| anyhow!("{}", e))?;
+        environment.set_metatable(Some(metatable)).map_err(|e| anyhow!("{}", e))?;
         
-        // The mlua library doesn't seem to be friendly with anyhow so we still need to use map_err 
-        // on each Result returning function from them.
-        lua.lua.load(&config_script).exec().map_err(|e| anyhow!("Failed to interpret configuration file: \n{}\n", e))?;
+        let script = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read \"{}\": {}", path.display(), e))?;
+        lua.lua.load(&script)
+            .set_name(path.to_string_lossy())
+            .set_environment(environment.clone())
+            .exec()
+            .map_err(|e| anyhow!("Failed to interpret \"{}\": \n{}\n", path.display(), e))?;
         
+        Ok(environment)
+    }
+    
+    /// Evaluate every module listed in the `imports` of `table` and merge it into `config`.
+    /// 
+    /// Modules can import other modules, those are merged before the module importing them. Paths
+    /// are relative to the configuration directory and every module is only merged once.
+    fn merge_imports(lua: &GoatLua,
+                     merge: &mut Merge,
+                     config: &mlua::Table,
+                     table: &mlua::Table,
+                     directory: &Path,
+                     visited: &mut HashSet<PathBuf>) -> anyhow::Result<()> {
+        // Raw, the environment of a module falls through to the globals of config.lua.
+        let Some(imports) = table.raw_get::<Option<mlua::Table>>("imports").map_err(|e| anyhow!("{}", e))? else {
+            return Ok(());
+        };
+        
+        for module in imports.sequence_values::<String>() {
+            let module = module.map_err(|e| anyhow!("{}", e))?;
+            let module_path = directory.join(&module);
+            
+            if !visited.insert(module_path.clone()) {
+                continue;
+            }
+            
+            let environment = Self::evaluate_module(lua, &module_path)?;
+            Self::merge_imports(lua, merge, config, &environment, directory, visited)?;
+            
+            // `imports` is only meaningful to the file that declares it.
+            environment.raw_remove("imports").map_err(|e| anyhow!("{}", e))?;
+            merge.merge(config, &environment, &module, true)?;
+        }
+        
+        Ok(())
+    }
+    
+    /// Create a `Config` instance from the globals of an evaluated configuration file, see
+    /// `evaluate`.
+    pub fn from_table(globals: &mlua::Table) -> anyhow::Result<Self> {
         let mut config = Config::default();
         
+        if let Ok(hostname) = globals.get::<String>("hostname") {
+            config.hostname = hostname;
+        }
+        
         // libc standards require a lower than 64 length hostname. Unfortunately we need to support 
         // this standard for now. DNS FQDN restrictions have a cap of 255 characters, this might be
         // something we will need to change in the future so IT guys with huge domain name 
@@ -83,12 +389,99 @@ impl Config {
 
         if let Ok(packages_value) = globals.get::<Value>("packages").map_err(|e| anyhow!("{}", e)) {
             if let Some(packages_list) = packages_value.as_table() {
-                config.packages = Some(packages_list.sequence_values::<String>()
-                        .collect::<Result<Vec<_>, _>>()
-                        .map_err(|err| anyhow!("{}", err))?)
+                let mut packages = vec![];
+                
+                for package in packages_list.sequence_values::<Value>() {
+                    match package.map_err(|e| anyhow!("{}", e))? {
+                        Value::String(name) => packages.push(name.to_str().map_err(|e| anyhow!("{}", e))?.to_owned()),
+                        Value::Table(pin) => {
+                            let pin = PackagePin {
+                                name: pin.get("name").map_err(|e| anyhow!("Package table without a name: {}", e))?,
+                                version: pin.get("version").map_err(|e| anyhow!("{}", e))?,
+                                hold: pin.get::<Option<bool>>("hold").map_err(|e| anyhow!("{}", e))?.unwrap_or(false)
+                            };
+                            
+                            packages.push(pin.name.clone());
+                            config.package_pins.push(pin);
+                        },
+                        value => return Err(anyhow!("Invalid entry in packages: {:?}", value))
+                    }
+                }
+                
+                config.packages = Some(packages);
             }
         } 
         
+        if let Some(repositories) = globals.get::<Option<mlua::Table>>("repositories").map_err(|e| anyhow!("{}", e))? {
+            for repository in repositories.sequence_values::<mlua::Table>() {
+                let repository = repository.map_err(|e| anyhow!("{}", e))?;
+                
+                let name: String = repository.get("name").map_err(|e| anyhow!("Repository without a name: {}", e))?;
+                let urls = match repository.get::<Value>("url").map_err(|e| anyhow!("{}", e))? {
+                    Value::String(url) => vec![url.to_str().map_err(|e| anyhow!("{}", e))?.to_owned()],
+                    Value::Table(urls) => urls.sequence_values::<String>()
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|e| anyhow!("{}", e))?,
+                    _ => return Err(anyhow!("Repository \"{}\" needs a url", name))
+                };
+                
+                config.repositories.push(Repository {
+                    name,
+                    urls,
+                    key: repository.get("key").map_err(|e| anyhow!("{}", e))?,
+                    priority: repository.get::<Option<i64>>("priority").map_err(|e| anyhow!("{}", e))?.unwrap_or(99)
+                });
+            }
+            
+            config.repositories.sort_by_key(|repository| repository.priority);
+        }
+        
+        if let Some(build_user) = globals.get::<Option<String>>("build_user").map_err(|e| anyhow!("{}", e))? {
+            config.build_user = Some(build_user);
+        }
+        
+        if let Some(install_strategy) = globals.get::<Option<String>>("install_strategy").map_err(|e| anyhow!("{}", e))? {
+            let batch_size = globals.get::<Option<usize>>("install_batch_size").map_err(|e| anyhow!("{}", e))?;
+            if batch_size == Some(0) {
+                return Err(anyhow!("install_batch_size has to be at least 1"));
+            }
+            
+            config.install_strategy = match install_strategy.as_str() {
+                "single" => InstallStrategy::Single,
+                "batches" => InstallStrategy::Batches(batch_size.unwrap_or(DEFAULT_INSTALL_BATCH_SIZE)),
+                // Without a batch size everything is tried at once first.
+                "fallback" => InstallStrategy::Fallback(batch_size.unwrap_or(usize::MAX)),
+                _ => return Err(anyhow!("Unknown install_strategy \"{}\", expected \"single\", \"batches\" or \"fallback\"", install_strategy))
+            };
+        }
+        
+        if let Some(profiles) = globals.get::<Option<Vec<String>>>("profiles").map_err(|e| anyhow!("{}", e))? {
+            config.profiles = profiles;
+        }
+        
+        // Reading it as a bool would take any value other than false and nil as true.
+        match globals.get::<Value>("demote_unneeded_packages").map_err(|e| anyhow!("{}", e))? {
+            Value::Nil => {},
+            Value::Boolean(demote_unneeded_packages) => config.demote_unneeded_packages = demote_unneeded_packages,
+            value => return Err(anyhow!("Invalid demote_unneeded_packages: expected a boolean, got {}", value.type_name()))
+        }
+        
+        match globals.get::<Value>("full_upgrade_on_repository_change").map_err(|e| anyhow!("{}", e))? {
+            Value::Nil => {},
+            Value::Boolean(full_upgrade) => config.full_upgrade_on_repository_change = full_upgrade,
+            value => return Err(anyhow!("Invalid full_upgrade_on_repository_change: expected a boolean, got {}", value.type_name()))
+        }
+        
+        match globals.get::<Value>("strict_packages").map_err(|e| anyhow!("{}", e))? {
+            Value::Nil => {},
+            Value::Boolean(strict_packages) => config.strict_packages = strict_packages,
+            value => return Err(anyhow!("Invalid strict_packages: expected a boolean, got {}", value.type_name()))
+        }
+        
+        if let Some(hooks) = globals.get::<Option<mlua::Table>>("hooks").map_err(|e| anyhow!("{}", e))? {
+            config.hooks = Hooks::from_table(&hooks)?;
+        }
+        
         Ok(config)
     }
 }
\ No newline at end of file
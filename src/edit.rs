@@ -0,0 +1,112 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use anyhow::anyhow;
+use regex::Regex;
+use crate::goat::Goat;
+use crate::lua_writer::LuaSource;
+// edit.rs
+//
+// All logic related to the `add` and `remove` subcommands should be placed here. These edit the
+// configuration file in place, see `LuaSource`.
+
+/// Every module `source` lists in `imports`, modules imported by those included, in the order
+/// they are listed. Read without evaluating anything, like `Config::merge_imports` would resolve
+/// them.
+fn imported_files(source: &LuaSource,
+                  directory: &Path,
+                  visited: &mut HashSet<PathBuf>) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+
+    for module in source.list_values("imports")? {
+        let module_file = directory.join(module);
+        if !visited.insert(module_file.clone()) || !module_file.exists() {
+            continue;
+        }
+
+        let module = LuaSource::load(&module_file)?;
+        files.push(module_file);
+        files.extend(imported_files(&module, directory, visited)?);
+    }
+
+    Ok(files)
+}
+
+impl Goat {
+    /// Find the file that assigns the `packages` global.
+    ///
+    /// This is config.lua itself or one of its modules, either listed in `imports` (like the ones
+    /// written by `goat import`) or pulled in with `require`. If no file assigns it yet, config.lua
+    /// is returned.
+    pub fn packages_file(&self) -> anyhow::Result<PathBuf> {
+        let configuration_directory = &self.directories["configuration_directory"];
+        let config_file = configuration_directory.join(&self.files["config_file"]);
+
+        let config = LuaSource::load(&config_file)?;
+        if config.has_global("packages") {
+            return Ok(config_file);
+        }
+
+        // The configuration isn't evaluated here, so its sources aren't known.
+        let mut modules = imported_files(&config, configuration_directory, &mut HashSet::new())?;
+
+        let require_regex = Regex::new(r#"require\s*\(?\s*["']([^"']+)["']"#)?;
+        for module in require_regex.captures_iter(config.as_str()) {
+            modules.push(configuration_directory.join(format!("{}.lua", module[1].replace('.', "/"))));
+        }
+
+        for module_file in modules {
+            if module_file.exists() && LuaSource::load(&module_file)?.has_global("packages") {
+                return Ok(module_file);
+            }
+        }
+
+        Ok(config_file)
+    }
+
+    /// Add packages to the configuration, keeping the `packages` table sorted if it already is.
+    ///
+    /// Every package is validated against the package manager first, nothing is written if any of
+    /// them is unknown.
+    pub fn add_packages(&self, packages: &[String]) -> anyhow::Result<()> {
+        // Groups aren't in the repositories by name, their members are.
+        let package_names: Vec<&str> = packages.iter().map(|package| package.as_str()).collect();
+        let expanded_packages = self.package_manager.expand_groups(&package_names)?;
+        let expanded_packages: Vec<&str> = expanded_packages.iter().map(|package| package.as_str()).collect();
+        let unknown_packages = self.package_manager.unknown_packages(&expanded_packages)?;
+
+        if !unknown_packages.is_empty() {
+            let unknown_packages: Vec<String> = unknown_packages.iter().map(|package| package.to_string()).collect();
+            return Err(anyhow!("Unknown package(s): {}", unknown_packages.join(", ")));
+        }
+
+        let path = self.packages_file()?;
+        self.edit_packages(&path, packages, |source, package| source.list_insert("packages", package), "Added", "is already in")
+    }
+
+    /// Remove packages from the configuration.
+    pub fn remove_packages(&self, packages: &[String]) -> anyhow::Result<()> {
+        let path = self.packages_file()?;
+        self.edit_packages(&path, packages, |source, package| source.list_remove("packages", package), "Removed", "isn't in")
+    }
+
+    /// Apply `edit` for each package to the `packages` table of `path` and save the result.
+    /// `edit` returns false when the package was skipped.
+    fn edit_packages(&self,
+                     path: &Path,
+                     packages: &[String],
+                     edit: impl Fn(&mut LuaSource, &str) -> anyhow::Result<bool>,
+                     done: &str,
+                     skipped: &str) -> anyhow::Result<()> {
+        let mut source = LuaSource::load(path)?;
+
+        for package in packages {
+            if edit(&mut source, package)? {
+                log::info!("{} \"{}\"", done, package);
+            } else {
+                log::warn!("\"{}\" {} \"{}\", skipping.", package, skipped, path.display());
+            }
+        }
+
+        source.save(path)
+    }
+}
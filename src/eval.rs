@@ -0,0 +1,97 @@
+use anyhow::anyhow;
+use goat_lua::GoatLua;
+use serde::Serialize;
+use crate::config::Config;
+use crate::goat::Goat;
+use crate::lua_writer::{LuaChunk, LuaValue};
+use crate::package_manager::PackageManager;
+use crate::service_manager::ServiceManager;
+// eval.rs
+//
+// All logic related to the `eval` subcommand should be placed here.
+
+/// How `goat eval` prints the evaluated configuration.
+pub enum EvalFormat {
+    /// Every global the configuration ends up with, as lua that can be used as a config.lua.
+    Lua,
+
+    /// The parsed `Config` along with the selected package manager and service manager, as JSON.
+    Json,
+
+    /// The file each item came from.
+    Sources
+}
+
+/// Everything `goat` resolved for a host, see `EvalFormat::Json`.
+#[derive(Serialize)]
+pub struct Evaluation<'a> {
+    pub host: Option<&'a str>,
+    pub package_manager_file: Option<&'a str>,
+    pub service_manager_file: Option<&'a str>,
+    pub config: &'a Config,
+    pub package_manager: &'a PackageManager,
+    pub service_manager: &'a ServiceManager
+}
+
+impl Goat {
+    /// Evaluate the configuration as it would be on `host` (modules, host overlay and profiles
+    /// included) and render it in `format`.
+    ///
+    /// `profiles` are enabled on top of the configured ones. Nothing on the system is touched.
+    pub fn eval(&self, host: Option<&str>, profiles: &[String], format: EvalFormat) -> anyhow::Result<String> {
+        let config_file = self.directories["configuration_directory"].join(&self.files["config_file"]);
+
+        let lua = GoatLua::create()?;
+        let (table, sources) = Config::evaluate(&lua, &config_file, host, profiles)?;
+
+        // Make sure the result is a valid configuration, not just valid lua.
+        let mut config = Config::from_table(&table)?;
+        config.sources = sources;
+
+        match format {
+            EvalFormat::Json => {
+                let evaluation = Evaluation {
+                    host,
+                    package_manager_file: self.cache.package_manager_configuration_file.as_deref(),
+                    service_manager_file: self.cache.service_manager_configuration_file.as_deref(),
+                    config: &config,
+                    package_manager: &self.package_manager,
+                    service_manager: &self.service_manager
+                };
+
+                Ok(format!("{}\n", serde_json::to_string_pretty(&evaluation)?))
+            },
+            EvalFormat::Sources => {
+                let width = config.sources.keys().map(|item| item.len()).max().unwrap_or(0);
+
+                Ok(config.sources
+                    .iter()
+                    .map(|(item, source)| format!("{:width$}  {}\n", item, source, width = width))
+                    .collect())
+            },
+            EvalFormat::Lua => {
+                let mut globals: Vec<(String, mlua::Value)> = table.pairs::<String, mlua::Value>()
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| anyhow!("{}", e))?;
+                globals.sort_by(|a, b| a.0.cmp(&b.0));
+
+                let mut chunk = LuaChunk::new();
+                chunk.comment(&format!(
+                    "Evaluated configuration for host \"{}\"\nPackage manager: {}, service manager: {}",
+                    host.unwrap_or(""),
+                    self.cache.package_manager_configuration_file.as_deref().unwrap_or("unknown"),
+                    self.cache.service_manager_configuration_file.as_deref().unwrap_or("unknown")
+                ));
+
+                for (name, value) in globals {
+                    let value = LuaValue::from_lua_value(&value);
+                    if value != LuaValue::Nil {
+                        chunk.blank().assign(&name, value);
+                    }
+                }
+
+                Ok(chunk.render().to_owned())
+            }
+        }
+    }
+}
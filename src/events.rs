@@ -0,0 +1,160 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use crate::stage::{StageDiff, StageReport};
+// events.rs
+//
+// All logic related to the `--output json` event stream should be placed here.
+
+/// How `goat` reports what it is doing.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    /// Human readable log lines.
+    #[default]
+    Text,
+
+    /// One JSON object per line on stdout, log messages included.
+    Json
+}
+
+/// Something that happened during a sync, written as a single JSON line with `--output json`.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    SyncStart {
+        stages: Vec<String>
+    },
+
+    StageStart {
+        stage: &'a str
+    },
+
+    /// The stage applied. `changes` is what the stage was about to change, taken right before it
+    /// ran.
+    StageFinish {
+        stage: &'a str,
+        duration_ms: u64,
+        changes: &'a StageDiff,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        report: Option<&'a StageReport>
+    },
+
+    StageSkip {
+        stage: &'a str,
+        duration_ms: u64,
+        reason: &'a str
+    },
+
+    StageError {
+        stage: &'a str,
+        duration_ms: u64,
+        error: String
+    },
+
+    SyncFinish {
+        duration_ms: u64,
+        success: bool,
+        /// Items that failed or were skipped across every stage.
+        failed: usize
+    },
+
+    Log {
+        level: String,
+        target: String,
+        message: String
+    }
+}
+
+/// Set once JSON output is on, for output that doesn't go through a `Goat` (see `json_output`).
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Replaces lua's `print` so it writes to stderr, see `redirect_print`.
+const PRINT_TO_STDERR: &str = r#"
+print = function(...)
+    local values = table.pack(...)
+    for index = 1, values.n do
+        values[index] = tostring(values[index])
+    end
+    io.stderr:write(table.concat(values, "\t", 1, values.n), "\n")
+end
+"#;
+
+/// An event along with when it happened.
+#[derive(Serialize)]
+struct Timestamped<'a> {
+    timestamp_ms: u64,
+    #[serde(flatten)]
+    event: &'a Event<'a>
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Milliseconds in `duration`, for the `duration_ms` fields.
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+/// Serialize an event into a single line of JSON.
+fn to_line(event: &Event) -> String {
+    let timestamped = Timestamped {
+        timestamp_ms: now_ms(),
+        event
+    };
+
+    // None of the events contain anything serde_json can't serialize.
+    serde_json::to_string(&timestamped).unwrap_or_default()
+}
+
+/// Write `event` to stdout.
+pub fn emit(event: &Event) {
+    println!("{}", to_line(event));
+}
+
+/// Returns true if stdout is reserved for events. Commands and lua code that print have to write to
+/// stderr instead.
+pub fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Make `print` in `lua` write to stderr when stdout is reserved for events.
+pub fn redirect_print(lua: &mlua::Lua) -> anyhow::Result<()> {
+    if json_output() {
+        lua.load(PRINT_TO_STDERR).exec().map_err(|e| anyhow::anyhow!("{}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Set up `env_logger` for `format`, showing info level and above by default. With JSON output
+/// every log message becomes a `log` event on stdout so the stream stays parseable.
+pub fn init_logger(format: OutputFormat) {
+    JSON_OUTPUT.store(format == OutputFormat::Json, Ordering::Relaxed);
+
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default()
+        .default_filter_or("info"));
+
+    match format {
+        OutputFormat::Text => {
+            builder.format_timestamp(None);
+        },
+        OutputFormat::Json => {
+            builder
+                .target(env_logger::Target::Stdout)
+                .format(|buffer, record| {
+                    writeln!(buffer, "{}", to_line(&Event::Log {
+                        level: record.level().as_str().to_lowercase(),
+                        target: record.target().to_owned(),
+                        message: record.args().to_string()
+                    }))
+                });
+        }
+    }
+
+    builder.init();
+}
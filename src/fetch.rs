@@ -0,0 +1,65 @@
+use std::fs;
+use anyhow::anyhow;
+use crate::goat::Goat;
+use crate::stage::Packages;
+// fetch.rs
+//
+// All logic related to the `fetch` subcommand and offline syncs should be placed here.
+
+impl Goat {
+    /// Download every configured package and its dependencies into the package cache so
+    /// `goat sync --offline` can install them without network access.
+    /// 
+    /// The cache is emptied first, it only ever holds the packages of the current configuration.
+    /// Pinned packages are fetched at their pinned version, fetching fails if any of them can't be.
+    pub fn fetch(&self) -> anyhow::Result<()> {
+        let Some(packages) = Packages::configured(self)? else {
+            log::warn!("The configuration doesn't manage packages, nothing to fetch.");
+            return Ok(());
+        };
+        
+        let cache = &self.directories["package_cache_directory"];
+        
+        if cache.exists() {
+            fs::remove_dir_all(cache)?;
+        }
+        fs::create_dir_all(cache)?;
+        
+        log::info!("Downloading {} package(s) into \"{}\"...", packages.len(), cache.display());
+        
+        // Next to the current version the pinned one would only be replaced again.
+        let pins: Vec<(&str, &str)> = self.config.package_pins
+            .iter()
+            .filter_map(|pin| Some((pin.name.as_str(), pin.version.as_deref()?)))
+            .collect();
+        
+        let packages: Vec<&str> = packages
+            .iter()
+            .map(|package| package.as_str())
+            .filter(|package| !pins.iter().any(|(name, _)| name == package))
+            .collect();
+        if !packages.is_empty() {
+            self.package_manager.download(&packages, cache)?;
+        }
+        
+        let mut failed = vec![];
+        for (name, version) in pins {
+            if let Err(e) = self.package_manager.download_version(name, version, cache) {
+                failed.push(format!("\"{}\" {} ({})", name, version, e));
+            }
+        }
+        
+        if !failed.is_empty() {
+            return Err(anyhow!("Can't fetch the pinned version of {}", failed.join(", ")));
+        }
+        
+        let downloaded = fs::read_dir(cache)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .count();
+        
+        log::info!("Fetched {} file(s), sync offline with `goat sync --offline`.", downloaded);
+        
+        Ok(())
+    }
+}
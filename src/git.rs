@@ -0,0 +1,261 @@
+use std::fs;
+use std::path::Path;
+use anyhow::anyhow;
+use crate::command::{self, shell_quote};
+use crate::config::Config;
+use crate::goat::Goat;
+// git.rs
+//
+// All logic related to git backed configuration directories (`goat sync --from-git`) should be
+// placed here.
+
+/// The commit `directory` is checked out at and whether it has uncommitted changes, `None` if
+/// it isn't a git repository.
+pub fn git_state(directory: &Path) -> Option<(String, bool)> {
+    let directory = shell_quote(&directory.to_string_lossy());
+
+    let commit = command::output_lines(&format!("git -C {} rev-parse HEAD 2>/dev/null", directory))
+        .ok()?
+        .into_iter()
+        .next()?;
+    let dirty = command::output_lines(&format!("git -C {} status --porcelain 2>/dev/null", directory))
+        .map(|changes| !changes.is_empty())
+        .unwrap_or(false);
+
+    Some((commit, dirty))
+}
+
+/// Check out `rev` of the repository at `source` (a path or url) into `directory`, replacing
+/// anything that was there. Returns the commit that was checked out.
+///
+/// `rev` can be anything git resolves to a commit, branches of `source` included.
+pub fn checkout(source: &str, rev: &str, directory: &Path) -> anyhow::Result<String> {
+    if directory.exists() {
+        fs::remove_dir_all(directory)?;
+    }
+
+    let quoted_directory = shell_quote(&directory.to_string_lossy());
+
+    // `--` keeps a source starting with a dash from being read as an option.
+    command::run(&format!("git clone --quiet --no-checkout -- {} {}", shell_quote(source), quoted_directory))?;
+
+    // Only the default branch exists locally after a clone, the others are `origin/<branch>`.
+    let commit = [rev.to_owned(), format!("origin/{}", rev)]
+        .iter()
+        .find_map(|candidate| {
+            command::output_lines(&format!(
+                "git -C {} rev-parse --verify --quiet {} 2>/dev/null",
+                quoted_directory,
+                shell_quote(&format!("{}^{{commit}}", candidate))
+            )).ok()?.into_iter().next()
+        })
+        .ok_or_else(|| anyhow!("\"{}\" is not a revision of \"{}\"", rev, source))?;
+
+    command::run(&format!("git -C {} checkout --quiet --detach {}", quoted_directory, commit))?;
+
+    Ok(commit)
+}
+
+impl Goat {
+    /// Same as `load` but the configuration is checked out from revision `rev` of the git
+    /// repository at `source` into the staging directory and evaluated from there.
+    pub fn load_from_git(recache: bool, source: &str, rev: &str, profiles: &[String]) -> anyhow::Result<Self> {
+        let mut goat = Self::load_system(recache)?;
+        goat.load_git_config(source, rev, profiles)?;
+
+        Ok(goat)
+    }
+
+    /// Check out `rev` of `source` into the staging directory and use it as the configuration
+    /// directory. Custom stages come from its `custom_stages` directory, a revision without one
+    /// keeps the system's custom stages.
+    fn load_git_config(&mut self, source: &str, rev: &str, profiles: &[String]) -> anyhow::Result<()> {
+        // Only committed changes are synced, point that out when syncing from a working tree.
+        if let Some((_, true)) = git_state(Path::new(source)) {
+            log::warn!("\"{}\" has uncommitted changes, they won't be synced.", source);
+        }
+
+        let staging_directory = self.directories["staging_directory"].clone();
+        let commit = checkout(source, rev, &staging_directory)?;
+        log::info!("Checked out \"{}\" at {} ({})", source, rev, commit);
+
+        let config_file = staging_directory.join(&self.files["config_file"]);
+        if !config_file.exists() {
+            return Err(anyhow!("\"{}\" has no {} at {}", source, self.files["config_file"].display(), rev));
+        }
+
+        self.config = Config::from_file(&config_file, profiles)?;
+
+        let custom_stages = staging_directory.join("custom_stages");
+        if custom_stages.is_dir() {
+            let system_custom_stages = &self.directories["custom_stages"];
+            if system_custom_stages.read_dir().is_ok_and(|mut entries| entries.next().is_some()) {
+                log::warn!(
+                    "\"{}\" brings its own custom stages, the ones in \"{}\" are ignored.",
+                    source,
+                    system_custom_stages.display()
+                );
+            }
+
+            self.directories.insert(String::from("custom_stages"), custom_stages);
+        }
+
+        self.directories.insert(String::from("configuration_directory"), staging_directory);
+        self.git_source = Some(source.to_owned());
+
+        Ok(())
+    }
+
+    /// Warn about syncing a configuration directory with uncommitted changes, the history would
+    /// point at a commit that isn't what was synced.
+    pub fn warn_if_dirty(&self) {
+        let configuration_directory = &self.directories["configuration_directory"];
+
+        if let Some((commit, true)) = git_state(configuration_directory) {
+            log::warn!(
+                "\"{}\" has uncommitted changes on top of {}, commit them so the history can tell what was synced.",
+                configuration_directory.display(),
+                commit
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::sync::Mutex;
+    use super::*;
+    use crate::cache::Cache;
+    use crate::events::OutputFormat;
+    use crate::from_file::FromFile;
+    use crate::package_manager::PackageManager;
+    use crate::service_manager::ServiceManager;
+
+    /// An empty directory of its own for the test `name`.
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("goat-git-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).expect("can't create the test directory");
+        directory
+    }
+
+    fn git(directory: &Path, arguments: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(["-c", "user.name=goat", "-c", "user.email=goat@localhost", "-C"])
+            .arg(directory)
+            .args(arguments)
+            .output()
+            .expect("git isn't installed");
+        assert!(output.status.success(), "git {:?} failed: {}", arguments, String::from_utf8_lossy(&output.stderr));
+
+        String::from_utf8_lossy(&output.stdout).trim().to_owned()
+    }
+
+    /// A bare repository with a config.lua on `main` and a custom stage on the `other` branch.
+    /// Returns the path of the bare repository and the commits of both branches.
+    fn bare_repository(root: &Path) -> (PathBuf, String, String) {
+        let work = root.join("work");
+        fs::create_dir_all(&work).unwrap();
+        git(&work, &["init", "--quiet", "--initial-branch=main"]);
+
+        fs::write(work.join("config.lua"), "hostname = \"main-host\"\n").unwrap();
+        git(&work, &["add", "-A"]);
+        git(&work, &["commit", "--quiet", "-m", "main"]);
+        let main = git(&work, &["rev-parse", "HEAD"]);
+
+        git(&work, &["checkout", "--quiet", "-b", "other"]);
+        fs::write(work.join("config.lua"), "hostname = \"other-host\"\n").unwrap();
+        fs::create_dir_all(work.join("custom_stages")).unwrap();
+        fs::write(work.join("custom_stages/greet.lua"), "stage = { apply = function(ctx) end }\n").unwrap();
+        git(&work, &["add", "-A"]);
+        git(&work, &["commit", "--quiet", "-m", "other"]);
+        let other = git(&work, &["rev-parse", "HEAD"]);
+        git(&work, &["checkout", "--quiet", "main"]);
+
+        let bare = root.join("config.git");
+        git(root, &["clone", "--quiet", "--bare", "--", &work.to_string_lossy(), &bare.to_string_lossy()]);
+
+        (bare, main, other)
+    }
+
+    /// A `Goat` that keeps every directory inside `root`.
+    fn test_goat(root: &Path) -> Goat {
+        Goat {
+            directories: HashMap::from([
+                (String::from("staging_directory"), root.join("staging")),
+                (String::from("configuration_directory"), root.join("config")),
+                (String::from("custom_stages"), root.join("custom_stages"))
+            ]),
+            files: Goat::get_files(),
+            cache: Cache::default(),
+            package_manager: PackageManager::from_file(&PathBuf::from("package_managers/pacman.lua")).unwrap(),
+            service_manager: ServiceManager::from_file(&PathBuf::from("service_managers/systemd.lua")).unwrap(),
+            config: Config::default(),
+            offline: false,
+            output: OutputFormat::Text,
+            git_source: None,
+            package_lock: Mutex::new(())
+        }
+    }
+
+    #[test]
+    fn checkout_resolves_the_default_branch_other_branches_and_commits() {
+        let root = test_directory("checkout");
+        let (bare, main, other) = bare_repository(&root);
+        let source = bare.to_string_lossy();
+        let staging = root.join("staging");
+
+        assert_eq!(checkout(&source, "HEAD", &staging).unwrap(), main);
+        assert_eq!(fs::read_to_string(staging.join("config.lua")).unwrap(), "hostname = \"main-host\"\n");
+
+        // Only exists as `origin/other` in the clone.
+        assert_eq!(checkout(&source, "other", &staging).unwrap(), other);
+        assert!(staging.join("custom_stages/greet.lua").exists());
+
+        assert_eq!(checkout(&source, &main[..12], &staging).unwrap(), main);
+        assert!(!staging.join("custom_stages").exists());
+
+        let error = checkout(&source, "missing", &staging).unwrap_err();
+        assert_eq!(error.to_string(), format!("\"missing\" is not a revision of \"{}\"", source));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn load_git_config_uses_the_checkout_and_its_custom_stages() {
+        let root = test_directory("load");
+        let (bare, _, other) = bare_repository(&root);
+        let source = bare.to_string_lossy();
+
+        let mut goat = test_goat(&root);
+        goat.load_git_config(&source, "other", &[]).unwrap();
+
+        let staging = root.join("staging");
+        assert_eq!(goat.config.hostname, "other-host");
+        assert_eq!(goat.directories["configuration_directory"], staging);
+        assert_eq!(goat.directories["custom_stages"], staging.join("custom_stages"));
+        assert_eq!(goat.git_source.as_deref(), Some(source.as_ref()));
+        assert_eq!(git_state(&staging), Some((other, false)));
+
+        let names: Vec<String> = goat.stages().unwrap().iter().map(|stage| stage.name()).collect();
+        assert!(names.contains(&String::from("greet.lua")), "{:?}", names);
+
+        // A revision without custom stages keeps the ones of the system.
+        let system_custom_stages = root.join("custom_stages");
+        fs::create_dir_all(&system_custom_stages).unwrap();
+        fs::write(system_custom_stages.join("local.lua"), "stage = { apply = function(ctx) end }\n").unwrap();
+
+        let mut goat = test_goat(&root);
+        goat.load_git_config(&source, "main", &[]).unwrap();
+        assert_eq!(goat.config.hostname, "main-host");
+        assert_eq!(goat.directories["custom_stages"], system_custom_stages);
+
+        let names: Vec<String> = goat.stages().unwrap().iter().map(|stage| stage.name()).collect();
+        assert!(names.contains(&String::from("local.lua")), "{:?}", names);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -2,10 +2,13 @@ use std::string::String;
 use std::path::{Path, PathBuf};
 use std::collections::HashMap;
 use std::fs::{self, DirEntry};
+use std::sync::Mutex;
 use anyhow::{anyhow};
 use crate::cache::Cache;
 use crate::config::Config;
+use crate::events::OutputFormat;
 use crate::from_file::FromFile;
+use crate::import::SystemSnapshot;
 use crate::package_manager::PackageManager;
 use crate::service_manager::ServiceManager;
 
@@ -24,25 +27,31 @@ pub struct Goat {
     pub cache: Cache,
     pub package_manager: PackageManager,
     pub service_manager: ServiceManager,
-    pub config: Config
+    pub config: Config,
+    
+    /// Install packages only from the package cache filled by `goat fetch`, without touching the
+    /// network.
+    pub offline: bool,
+    
+    /// Log lines for people or JSON events for other programs, see `events.rs`.
+    pub output: OutputFormat,
+    
+    /// The git repository the configuration was checked out from, see `load_from_git`.
+    pub git_source: Option<String>,
+    
+    /// Held while custom stages install packages, stages applied in parallel would otherwise run
+    /// into the package manager's own lock.
+    pub package_lock: Mutex<()>
 }
 
-/// Generate a config.lua file based on your current running system.
+/// Generate a config.lua file (and its modules) based on your current running system.
 /// 
-/// The following is currently included:
-/// - hostname
-/// - packages
-pub fn generate_system_config(package_manager: &PackageManager, path: &PathBuf) -> anyhow::Result<()> {
-    let explicit_packages: String = package_manager
-        .explicit_packages()?
-        .iter()
-        .map(|package| format!("    \"{}\"", package))
-        .collect::<Vec<String>>()
-        .join(",\n");
-    
-    fs::write(path, format!("hostname=\"{}\"\n\npackages = {{\n{}\n}}\n", fs::read_to_string("/etc/hostname")?.trim(), explicit_packages))?;
-    
-    Ok(())
+/// See `SystemSnapshot` for everything that is included. Modified `/etc` files are left out, use
+/// `goat import` to pick those. Existing modules are never overwritten.
+pub fn generate_system_config(package_manager: &PackageManager, 
+                              service_manager: &ServiceManager, 
+                              directory: &Path) -> anyhow::Result<()> {
+    SystemSnapshot::capture(package_manager, service_manager, &[])?.write(directory, false)
 }
 
 impl Goat {
@@ -59,7 +68,15 @@ impl Goat {
                 // Location of service manager configuration files.
                 (String::from("service_manager_configuration_directory"), PathBuf::from("service_managers")),
                 // Location of custom stages
-                (String::from("custom_stages"), PathBuf::from("custom_stages"))
+                (String::from("custom_stages"), PathBuf::from("custom_stages")),
+                // Location of packages downloaded by `goat fetch`
+                (String::from("package_cache_directory"), PathBuf::from("test_cache/pkgcache")),
+                // Location of the lua language server definitions written by `goat lsp-types`
+                (String::from("types_directory"), PathBuf::from("test_cache/types")),
+                // Location of the sync history
+                (String::from("history_directory"), PathBuf::from("test_cache/history")),
+                // Location configurations are checked out into by `goat sync --from-git`
+                (String::from("staging_directory"), PathBuf::from("test_cache/staging"))
             ])
         } else {
             HashMap::from([
@@ -72,7 +89,15 @@ impl Goat {
                 // Location of service manager configuration files.
                 (String::from("service_manager_configuration_directory"), PathBuf::from("/var/goat/service_managers")),
                 // Location of custom stages
-                (String::from("custom_stages"), PathBuf::from("/var/goat/custom_stages"))
+                (String::from("custom_stages"), PathBuf::from("/var/goat/custom_stages")),
+                // Location of packages downloaded by `goat fetch`
+                (String::from("package_cache_directory"), PathBuf::from("/var/goat/pkgcache")),
+                // Location of the lua language server definitions written by `goat lsp-types`
+                (String::from("types_directory"), PathBuf::from("/var/goat/types")),
+                // Location of the sync history
+                (String::from("history_directory"), PathBuf::from("/var/goat/history")),
+                // Location configurations are checked out into by `goat sync --from-git`
+                (String::from("staging_directory"), PathBuf::from("/var/goat/staging"))
             ])
         }
     }
@@ -80,7 +105,11 @@ impl Goat {
     pub fn get_files() -> HashMap<String, PathBuf> {
         HashMap::from([
             (String::from("config_file"), PathBuf::from("config.lua")),
-            (String::from("cache_file"), PathBuf::from("cache.json"))
+            (String::from("cache_file"), PathBuf::from("cache.json")),
+            (String::from("repl_history_file"), PathBuf::from("repl_history")),
+            (String::from("history_file"), PathBuf::from("syncs.jsonl")),
+            (String::from("stage_packages_file"), PathBuf::from("stage_packages.json")),
+            (String::from("held_packages_file"), PathBuf::from("held_packages.json"))
         ])
     }
 
@@ -139,8 +168,28 @@ impl Goat {
     /// Initialize the goat struct and confirm system vitals.
     /// 
     /// Running with the recache parameter set to true
-    /// will reset the cache files.
-    pub fn load(recache: bool) -> anyhow::Result<Self> {
+    /// will reset the cache files. `profiles` are enabled on top of the ones the configuration
+    /// lists.
+    pub fn load(recache: bool, profiles: &[String]) -> anyhow::Result<Self> {
+        let mut goat = Self::load_system(recache)?;
+        
+        let config_file = goat.directories["configuration_directory"].join(&goat.files["config_file"]);
+        if !config_file.exists() {
+            log::warn!("Generating configuration file \"{}\"...", config_file.display());
+            generate_system_config(&goat.package_manager, &goat.service_manager, &goat.directories["configuration_directory"])?;
+        }
+        
+        goat.config = Config::from_file(&config_file, profiles)?;
+        
+        Ok(goat)
+    }
+    
+    /// Same as `load` but the configuration file is neither generated nor evaluated, leaving
+    /// `config` at its default value.
+    /// 
+    /// This is for commands that have to work without a (valid) configuration file such as
+    /// `goat import`.
+    pub fn load_system(recache: bool) -> anyhow::Result<Self> {
         let directories = Self::get_directories();
         let files = Self::get_files();
         
@@ -156,6 +205,69 @@ impl Goat {
 
         let mut cache = Cache::load_cache(&cache_file)?;
         
+        let (package_manager, service_manager, cache_changed) = Self::load_managers(&directories, &mut cache)?;
+        
+        // Dump cache back into cache file. Originally we did this no matter what before loading
+        // the config file, but now we only write when needed.
+        if cache_changed {
+            cache.save_cache(&cache_file)?;
+        }
+        
+        Ok(Goat {
+            directories,
+            files,
+            cache,
+            package_manager,
+            service_manager,
+            config: Config::default(),
+            offline: false,
+            output: OutputFormat::Text,
+            git_source: None,
+            package_lock: Mutex::new(())
+        })
+    }
+    
+    /// Same as `load_system` but nothing is created or written: missing directories are left
+    /// alone and the cache is only read.
+    /// 
+    /// This is for commands that only inspect the configuration, like `goat eval`, so they don't
+    /// need root.
+    pub fn load_read_only() -> anyhow::Result<Self> {
+        let directories = Self::get_directories();
+        let files = Self::get_files();
+        
+        let cache_file = directories["cache_directory"].join(&files["cache_file"]);
+        let mut cache = if cache_file.exists() {
+            Cache::load_cache(&cache_file)?
+        } else {
+            Cache::default()
+        };
+        
+        let (package_manager, service_manager, _) = Self::load_managers(&directories, &mut cache)?;
+        
+        Ok(Goat {
+            directories,
+            files,
+            cache,
+            package_manager,
+            service_manager,
+            config: Config::default(),
+            offline: false,
+            output: OutputFormat::Text,
+            git_source: None,
+            package_lock: Mutex::new(())
+        })
+    }
+    
+    /// Load the package manager and service manager configurations named in `cache`, or find
+    /// the ones for this system if it doesn't name any yet.
+    /// 
+    /// The cache is updated with the files that were found, the returned bool is true if it
+    /// changed and should be saved.
+    fn load_managers(directories: &HashMap<String, PathBuf>,
+                     cache: &mut Cache) -> anyhow::Result<(PackageManager, ServiceManager, bool)> {
+        let mut cache_changed = false;
+        
         // TODO: Macroify these 2 let match statements.
         
         let package_manager: PackageManager = match Self::from_cached_file(
@@ -164,10 +276,8 @@ impl Goat {
         ) {
             Ok((package_manager, None)) => package_manager,
             Ok((package_manager, Some(file_path))) => {
-                // Dump cache back into cache file. Originally we did this no matter what before 
-                // loading the config file, but now we only write when needed.
                 cache.package_manager_configuration_file = Some(file_path);
-                cache.save_cache(&cache_file)?;
+                cache_changed = true;
                 
                 package_manager
             }
@@ -181,28 +291,13 @@ impl Goat {
             Ok((service_manager, None)) => service_manager,
             Ok((service_manager, Some(file_path))) => {
                 cache.service_manager_configuration_file = Some(file_path);
-                cache.save_cache(&cache_file)?;
+                cache_changed = true;
                 
                 service_manager
             }
             Err(e) => return Err(anyhow!(e))
         };
         
-        let config_file = directories["configuration_directory"].join(&files["config_file"]);
-        if !config_file.exists() {
-            log::warn!("Generating configuration file \"{}\"...", config_file.display());
-            generate_system_config(&package_manager, &config_file)?;
-        }
-        
-        let config = Config::from_file(&config_file)?;
-        
-        Ok(Goat {
-            directories,
-            files,
-            cache,
-            package_manager,
-            service_manager,
-            config
-        })
+        Ok((package_manager, service_manager, cache_changed))
     }
 }
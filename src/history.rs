@@ -0,0 +1,269 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::git::git_state;
+use crate::goat::Goat;
+use crate::stage::StageReport;
+// history.rs
+//
+// All logic related to the sync history (the audit log) and the `history` subcommand should be
+// placed here.
+
+/// What happened to a single stage during a sync.
+#[derive(Serialize, Deserialize)]
+pub struct StageRecord {
+    pub stage: String,
+
+    /// "done", "skipped" or "error".
+    pub result: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// Per item results of stages that report them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report: Option<StageReport>,
+
+    /// The stage applied but one of its `after` hooks failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hook_error: Option<String>
+}
+
+/// A single sync, one JSON line in the history file.
+#[derive(Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Seconds since the unix epoch the sync started at.
+    pub timestamp: u64,
+
+    /// The user that ran `sudo goat`, or the user running goat if it wasn't run through sudo.
+    pub user: String,
+
+    /// SHA-256 of the configuration file.
+    pub config_hash: Option<String>,
+
+    /// The commit the configuration directory was at, if it is a git repository.
+    pub git_commit: Option<String>,
+
+    /// Whether the configuration directory had uncommitted changes.
+    #[serde(default)]
+    pub git_dirty: bool,
+
+    /// The repository the configuration was checked out from with `--from-git`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_source: Option<String>,
+
+    #[serde(default)]
+    pub profiles: Vec<String>,
+
+    pub stages: Vec<StageRecord>,
+
+    /// Explicitly installed packages that appeared during the sync.
+    pub packages_added: Vec<String>,
+
+    /// Explicitly installed packages that disappeared during the sync.
+    pub packages_removed: Vec<String>,
+
+    pub success: bool,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// Explicitly installed packages before the sync, to find out what it added and removed.
+    #[serde(skip)]
+    explicit_packages: Vec<String>
+}
+
+/// Format seconds since the unix epoch as `YYYY-MM-DD HH:MM:SS UTC`.
+fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, seconds / 3600, seconds % 3600 / 60, seconds % 60)
+}
+
+impl HistoryEntry {
+    /// Start recording a sync of `goat`'s configuration.
+    pub fn begin(goat: &Goat) -> Self {
+        let configuration_directory = &goat.directories["configuration_directory"];
+        let config_file = configuration_directory.join(&goat.files["config_file"]);
+
+        let config_hash = fs::read(&config_file)
+            .ok()
+            .map(|contents| Sha256::digest(contents).iter().map(|byte| format!("{:02x}", byte)).collect());
+        let git = git_state(configuration_directory);
+
+        let explicit_packages = goat.package_manager.explicit_packages().unwrap_or_else(|e| {
+            log::warn!("Failed to list installed packages for the history: {}", e);
+            vec![]
+        });
+
+        HistoryEntry {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0),
+            user: std::env::var("SUDO_USER").unwrap_or_else(|_| {
+                let uid = nix::unistd::Uid::current();
+                nix::unistd::User::from_uid(uid).ok().flatten().map(|user| user.name).unwrap_or_else(|| uid.to_string())
+            }),
+            config_hash,
+            git_commit: git.as_ref().map(|(commit, _)| commit.clone()),
+            git_dirty: git.is_some_and(|(_, dirty)| dirty),
+            git_source: goat.git_source.clone(),
+            profiles: goat.config.profiles.clone(),
+            stages: vec![],
+            packages_added: vec![],
+            packages_removed: vec![],
+            success: false,
+            error: None,
+            explicit_packages
+        }
+    }
+
+    /// Record the outcome of the sync: `failed` is how many items failed or the error that
+    /// stopped it.
+    pub fn finish(&mut self, goat: &Goat, failed: &anyhow::Result<usize>) {
+        self.success = matches!(failed, Ok(0));
+        self.error = match failed {
+            Ok(0) => None,
+            Ok(failed) => Some(format!("{} item(s) failed or were skipped", failed)),
+            Err(e) => Some(e.to_string())
+        };
+
+        let after = match goat.package_manager.explicit_packages() {
+            Ok(packages) => packages,
+            Err(e) => {
+                log::warn!("Failed to list installed packages for the history: {}", e);
+                return;
+            }
+        };
+
+        let before: HashSet<&String> = self.explicit_packages.iter().collect();
+        let after_set: HashSet<&String> = after.iter().collect();
+
+        self.packages_added = after.iter().filter(|package| !before.contains(package)).cloned().collect();
+        self.packages_removed = self.explicit_packages.iter().filter(|package| !after_set.contains(package)).cloned().collect();
+    }
+
+    /// Print the entry for people, see `Goat::history`.
+    fn print(&self) {
+        let mut details = vec![format!("by {}", self.user)];
+
+        if let Some(hash) = &self.config_hash {
+            details.push(format!("config {}", &hash[..hash.len().min(12)]));
+        }
+        if let Some(commit) = &self.git_commit {
+            details.push(format!("commit {}{}", &commit[..commit.len().min(12)], if self.git_dirty { " (dirty)" } else { "" }));
+        }
+        if let Some(source) = &self.git_source {
+            details.push(format!("from {}", source));
+        }
+        if !self.profiles.is_empty() {
+            details.push(format!("profiles {}", self.profiles.join(",")));
+        }
+
+        println!("{}  {}  {}",
+            format_timestamp(self.timestamp),
+            if self.success { "ok" } else { "FAILED" },
+            details.join(", "));
+
+        for stage in &self.stages {
+            match (&stage.error, &stage.report) {
+                (Some(error), _) => println!("  {}: {} ({})", stage.stage, stage.result, error),
+                (None, Some(report)) => println!("  {}: {} done, {} failed, {} skipped",
+                    stage.stage, report.done.len(), report.failed.len(), report.skipped.len()),
+                (None, None) => println!("  {}: {}", stage.stage, stage.result)
+            }
+
+            if let Some(hook_error) = &stage.hook_error {
+                println!("  {}: {}", stage.stage, hook_error);
+            }
+        }
+
+        for package in &self.packages_added {
+            println!("  + {}", package);
+        }
+        for package in &self.packages_removed {
+            println!("  - {}", package);
+        }
+    }
+}
+
+impl Goat {
+    /// Append a finished sync to the history file.
+    pub fn record_history(&self, entry: &HistoryEntry) -> anyhow::Result<()> {
+        let history_file = self.directories["history_directory"].join(&self.files["history_file"]);
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&history_file)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+
+        Ok(())
+    }
+
+    /// Every recorded sync, oldest first. Lines that can't be read (a sync cut off while writing,
+    /// hand edits) are skipped with a warning.
+    pub fn history_entries(&self) -> anyhow::Result<Vec<HistoryEntry>> {
+        let history_file = self.directories["history_directory"].join(&self.files["history_file"]);
+        if !history_file.exists() {
+            return Ok(vec![]);
+        }
+
+        Ok(fs::read_to_string(&history_file)?
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .filter_map(|(index, line)| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    log::warn!("Skipping line {} of \"{}\": {}", index + 1, history_file.display(), e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Print the last `limit` syncs (every sync without a limit), oldest first.
+    pub fn history(&self, limit: Option<usize>, json: bool) -> anyhow::Result<()> {
+        let entries = self.history_entries()?;
+        let skip = limit.map(|limit| entries.len().saturating_sub(limit)).unwrap_or(0);
+
+        for entry in entries.iter().skip(skip) {
+            if json {
+                println!("{}", serde_json::to_string(entry)?);
+            } else {
+                entry.print();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_format_as_utc_dates() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(1709251199), "2024-02-29 23:59:59 UTC");
+        assert_eq!(format_timestamp(1735689599), "2024-12-31 23:59:59 UTC");
+        assert_eq!(format_timestamp(4107542400), "2100-03-01 00:00:00 UTC");
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::process::Command;
+use anyhow::anyhow;
+use goat_lua::GoatLua;
+use goat_lua_macro::LuaTypes;
+use mlua::Value;
+use crate::events::OutputFormat;
+use crate::goat::Goat;
+use crate::stage::Stage;
+// hooks.rs
+//
+// All logic related to the `hooks` table of config.lua should be placed here.
+
+/// Something to run at a hook point, a shell command or a lua function from config.lua.
+pub enum Hook {
+    Command(String),
+    Function(mlua::Function)
+}
+
+/// What a hook is running for, passed to lua hooks as a table and to commands as `GOAT_*`
+/// environment variables.
+#[derive(Default)]
+pub struct HookContext<'a> {
+    /// The hook point, like "before" or "post_sync".
+    pub hook: &'a str,
+    pub hostname: &'a str,
+    pub stage: Option<&'a str>,
+
+    /// "done", "skipped" or "failed".
+    pub result: Option<&'a str>,
+    pub error: Option<&'a str>
+}
+
+/// Hooks declared in config.lua:
+///
+/// `hooks = { pre_sync = "...", before = { Packages = function(ctx) ... end }, on_failure = { ... } }`
+///
+/// Every hook point takes a single hook or a list of them, run in order.
+#[derive(Default, LuaTypes)]
+#[lua(class = "goat.Hooks")]
+pub struct Hooks {
+    /// Run before the first stage, a failure stops the sync.
+    #[lua(ty = "goat.Hook|goat.Hook[]?")]
+    pub pre_sync: Vec<Hook>,
+
+    /// Run once every stage applied.
+    #[lua(ty = "goat.Hook|goat.Hook[]?")]
+    pub post_sync: Vec<Hook>,
+
+    /// Run before the stage with the given name, a failure fails the stage.
+    #[lua(ty = "table<string, goat.Hook|goat.Hook[]>?")]
+    pub before: HashMap<String, Vec<Hook>>,
+
+    /// Run after the stage with the given name. The stage already applied, a failure is reported
+    /// on its own and fails the sync once every stage ran.
+    #[lua(ty = "table<string, goat.Hook|goat.Hook[]>?")]
+    pub after: HashMap<String, Vec<Hook>>,
+
+    /// Run when a stage failed or items of a stage failed, failures are only logged.
+    #[lua(ty = "goat.Hook|goat.Hook[]?")]
+    pub on_failure: Vec<Hook>
+}
+
+/// Read a single hook or a list of them.
+fn hook_list(value: Value, name: &str) -> anyhow::Result<Vec<Hook>> {
+    match value {
+        Value::Nil => Ok(vec![]),
+        Value::String(command) => Ok(vec![Hook::Command(command.to_str().map_err(|e| anyhow!("{}", e))?.to_owned())]),
+        Value::Function(function) => Ok(vec![Hook::Function(function)]),
+        Value::Table(hooks) => hooks.sequence_values::<Value>()
+            .map(|hook| match hook.map_err(|e| anyhow!("{}", e))? {
+                Value::String(command) => Ok(Hook::Command(command.to_str().map_err(|e| anyhow!("{}", e))?.to_owned())),
+                Value::Function(function) => Ok(Hook::Function(function)),
+                value => Err(anyhow!("Invalid hook in hooks.{}: expected a command or a function, got {}", name, value.type_name()))
+            })
+            .collect(),
+        value => Err(anyhow!("Invalid hooks.{}: expected a command, a function or a list of them, got {}", name, value.type_name()))
+    }
+}
+
+/// Read a table of hooks keyed by stage name.
+fn stage_hooks(table: &mlua::Table, name: &str) -> anyhow::Result<HashMap<String, Vec<Hook>>> {
+    let mut hooks = HashMap::new();
+
+    if let Some(stages) = table.get::<Option<mlua::Table>>(name).map_err(|e| anyhow!("{}", e))? {
+        for pair in stages.pairs::<String, Value>() {
+            let (stage, value) = pair.map_err(|e| anyhow!("{}", e))?;
+            let list = hook_list(value, &format!("{}.{}", name, stage))?;
+            hooks.insert(stage, list);
+        }
+    }
+
+    Ok(hooks)
+}
+
+impl Hooks {
+    /// Read the `hooks` table of a configuration.
+    pub fn from_table(table: &mlua::Table) -> anyhow::Result<Self> {
+        let get = |name: &str| -> anyhow::Result<Vec<Hook>> {
+            hook_list(table.get::<Value>(name).map_err(|e| anyhow!("{}", e))?, name)
+        };
+
+        Ok(Hooks {
+            pre_sync: get("pre_sync")?,
+            post_sync: get("post_sync")?,
+            before: stage_hooks(table, "before")?,
+            after: stage_hooks(table, "after")?,
+            on_failure: get("on_failure")?
+        })
+    }
+}
+
+impl Hook {
+    /// Run the hook, commands write to stderr instead of stdout with `json_output` so the event
+    /// stream stays parseable.
+    fn run(&self, context: &HookContext, runtime: Option<&GoatLua>, json_output: bool) -> anyhow::Result<()> {
+        match self {
+            Hook::Command(command) => {
+                let mut process = Command::new("sh");
+                process.arg("-c").arg(command)
+                    .env("GOAT_HOOK", context.hook)
+                    .env("GOAT_HOSTNAME", context.hostname);
+
+                if json_output {
+                    process.stdout(std::io::stderr());
+                }
+
+                for (name, value) in [("GOAT_STAGE", context.stage), ("GOAT_RESULT", context.result), ("GOAT_ERROR", context.error)] {
+                    if let Some(value) = value {
+                        process.env(name, value);
+                    }
+                }
+
+                let status = process.status().map_err(|e| anyhow!("Failed to execute \"{}\": {}", command, e))?;
+                if !status.success() {
+                    return Err(anyhow!("Hook \"{}\" failed with {}", command, status));
+                }
+
+                Ok(())
+            },
+            Hook::Function(function) => {
+                let runtime = runtime.ok_or_else(|| anyhow!("The lua state the hook was defined in is gone"))?;
+                let table = runtime.lua.create_table().map_err(|e| anyhow!("{}", e))?;
+
+                table.set("hook", context.hook).map_err(|e| anyhow!("{}", e))?;
+                table.set("hostname", context.hostname).map_err(|e| anyhow!("{}", e))?;
+                table.set("stage", context.stage).map_err(|e| anyhow!("{}", e))?;
+                table.set("result", context.result).map_err(|e| anyhow!("{}", e))?;
+                table.set("error", context.error).map_err(|e| anyhow!("{}", e))?;
+
+                function.call::<()>(table).map_err(|e| anyhow!("{}", e))
+            }
+        }
+    }
+}
+
+impl Goat {
+    /// Run every hook in `hooks` in order, stopping at the first one that fails.
+    pub fn run_hooks(&self, hooks: &[Hook], context: HookContext) -> anyhow::Result<()> {
+        let context = HookContext {
+            hostname: &self.config.hostname,
+            ..context
+        };
+
+        for hook in hooks {
+            log::debug!("Running {} hook", context.hook);
+            hook.run(&context, self.config.runtime.as_ref(), self.output == OutputFormat::Json)?;
+        }
+
+        Ok(())
+    }
+
+    /// Make sure every stage named in `hooks.before` and `hooks.after` is one of `stages`, hooks of
+    /// a misspelled stage would never run.
+    pub fn check_stage_hooks(&self, stages: &[Box<dyn Stage>]) -> anyhow::Result<()> {
+        let names: Vec<String> = stages.iter().map(|stage| stage.name()).collect();
+        
+        for (hook, hooks) in [("before", &self.config.hooks.before), ("after", &self.config.hooks.after)] {
+            let mut unknown: Vec<&String> = hooks.keys().filter(|stage| !names.contains(stage)).collect();
+            unknown.sort();
+            
+            if let Some(stage) = unknown.first() {
+                return Err(anyhow!("hooks.{} names the unknown stage \"{}\", the stages are: {}", hook, stage, names.join(", ")));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Run the hooks declared for `stage` at `hook` ("before" or "after").
+    pub fn run_stage_hooks(&self, hook: &str, stage: &str, result: Option<&str>) -> anyhow::Result<()> {
+        let hooks = match hook {
+            "before" => &self.config.hooks.before,
+            _ => &self.config.hooks.after
+        };
+
+        match hooks.get(stage) {
+            Some(hooks) => self.run_hooks(hooks, HookContext {
+                hook,
+                stage: Some(stage),
+                result,
+                ..HookContext::default()
+            }).map_err(|e| anyhow!("{} hook of stage \"{}\" failed: {}", hook, stage, e)),
+            None => Ok(())
+        }
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use anyhow::anyhow;
+use crate::goat::Goat;
+use crate::lua_writer::{LuaChunk, LuaTable};
+use crate::package_manager::PackageManager;
+use crate::service_manager::ServiceManager;
+// import.rs
+//
+// All logic related to the `import` subcommand should be placed here.
+
+/// Users and groups with an id below this are considered system accounts unless `/etc/login.defs`
+/// says otherwise.
+const DEFAULT_ID_MIN: u32 = 1000;
+
+/// Ids at or above this are reserved (`nobody` is 65534).
+const ID_MAX: u32 = 65534;
+
+/// `/etc` files and directories holding secrets or machine identity, these are never picked up by
+/// `--all-etc` or a directory given with `--etc`.
+const SECRET_ETC_FILES: [&str; 12] = [
+    "/etc/shadow",
+    "/etc/shadow-",
+    "/etc/gshadow",
+    "/etc/gshadow-",
+    "/etc/machine-id",
+    "/etc/krb5.keytab",
+    "/etc/ssl/private",
+    "/etc/pacman.d/gnupg",
+    "/etc/NetworkManager/system-connections",
+    "/etc/wireguard",
+    "/etc/iwd",
+    "/etc/wpa_supplicant"
+];
+
+/// Returns true if `path` holds a secret, see `SECRET_ETC_FILES`. SSH host keys are matched by
+/// name.
+fn is_secret(path: &Path) -> bool {
+    let ssh_host_key = path.starts_with("/etc/ssh") && path
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("ssh_host_"));
+
+    ssh_host_key || SECRET_ETC_FILES.iter().any(|secret| path.starts_with(secret))
+}
+
+/// Returns true if anyone on the system can read `path`.
+fn is_world_readable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o004 != 0)
+}
+
+/// A non-system user account.
+pub struct SystemUser {
+    pub name: String,
+    pub uid: u32,
+    pub home: String,
+    pub shell: String,
+    /// Supplementary groups, the primary group is left out.
+    pub groups: Vec<String>
+}
+
+/// A non-system group.
+pub struct SystemGroup {
+    pub name: String,
+    pub gid: u32
+}
+
+/// Everything `goat import` knows how to capture from the running system.
+pub struct SystemSnapshot {
+    pub hostname: String,
+    pub packages: Vec<String>,
+    pub services: Vec<String>,
+    pub users: Vec<SystemUser>,
+    pub groups: Vec<SystemGroup>,
+    pub timezone: Option<String>,
+    pub locale: Option<String>,
+    pub keymap: Option<String>,
+    pub kernel_cmdline: Option<String>,
+    /// Modified `/etc` files to copy into the configuration directory.
+    pub files: Vec<PathBuf>
+}
+
+pub struct ImportOptions {
+    /// The directory to write config.lua and its modules into.
+    pub output: PathBuf,
+
+    /// Only candidates from `modified_etc_files` that are (or are inside) one of these paths get
+    /// imported. Secrets and files that aren't world readable have to be given by their own path.
+    pub etc_files: Vec<PathBuf>,
+
+    /// Import every candidate from `modified_etc_files` except secrets and files that aren't world
+    /// readable.
+    pub all_etc_files: bool,
+
+    /// Overwrite an existing config.lua and modules.
+    pub force: bool
+}
+
+/// Read a `KEY=value` formatted file such as `/etc/locale.conf` and return the value of `key`.
+fn read_key_value(path: &Path, key: &str) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()?
+        .lines()
+        .filter_map(|line| line.trim().split_once('='))
+        .find(|(name, _)| name.trim() == key)
+        .map(|(_, value)| value.trim().trim_matches('"').to_owned())
+}
+
+/// The lowest id of a non-system account. `login.defs` uses whitespace instead of `=`.
+fn id_min(key: &str) -> u32 {
+    fs::read_to_string("/etc/login.defs")
+        .ok()
+        .and_then(|contents| contents
+            .lines()
+            .filter_map(|line| line.split_once(char::is_whitespace))
+            .find(|(name, _)| *name == key)
+            .and_then(|(_, value)| value.trim().parse().ok()))
+        .unwrap_or(DEFAULT_ID_MIN)
+}
+
+/// Parse `/etc/passwd` and `/etc/group` for non-system users and groups.
+///
+/// Groups that only exist as the private group of a user (same name and id as the user's primary
+/// group) are skipped since creating the user creates them as well.
+fn users_and_groups() -> anyhow::Result<(Vec<SystemUser>, Vec<SystemGroup>)> {
+    let uid_min = id_min("UID_MIN");
+    let gid_min = id_min("GID_MIN");
+
+    // name, gid, members
+    let group_entries: Vec<(String, u32, Vec<String>)> = fs::read_to_string("/etc/group")?
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            Some((
+                fields.first()?.to_string(),
+                fields.get(2)?.parse().ok()?,
+                fields.get(3)?.split(',').filter(|m| !m.is_empty()).map(|m| m.to_owned()).collect()
+            ))
+        })
+        .collect();
+
+    let mut users = vec![];
+    let mut private_groups = HashSet::new();
+
+    for line in fs::read_to_string("/etc/passwd")?.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 7 {
+            continue;
+        }
+
+        let (Ok(uid), Ok(gid)) = (fields[2].parse::<u32>(), fields[3].parse::<u32>()) else {
+            continue;
+        };
+
+        if uid < uid_min || uid >= ID_MAX {
+            continue;
+        }
+
+        private_groups.insert((fields[0].to_owned(), gid));
+
+        users.push(SystemUser {
+            name: fields[0].to_owned(),
+            uid,
+            home: fields[5].to_owned(),
+            shell: fields[6].to_owned(),
+            groups: group_entries
+                .iter()
+                .filter(|(_, _, members)| members.iter().any(|member| member == fields[0]))
+                .map(|(name, _, _)| name.clone())
+                .collect()
+        });
+    }
+
+    let groups = group_entries
+        .into_iter()
+        .filter(|(name, gid, _)| *gid >= gid_min && *gid < ID_MAX && !private_groups.contains(&(name.clone(), *gid)))
+        .map(|(name, gid, _)| SystemGroup { name, gid })
+        .collect();
+
+    Ok((users, groups))
+}
+
+/// Every `/etc` file that is either a modified package configuration file or not owned by any
+/// package at all.
+pub fn modified_etc_files(package_manager: &PackageManager) -> anyhow::Result<Vec<PathBuf>> {
+    let mut candidates: Vec<PathBuf> = package_manager
+        .modified_files()?
+        .into_iter()
+        .map(PathBuf::from)
+        .filter(|path| path.starts_with("/etc"))
+        .collect();
+
+    let owned_files: HashSet<PathBuf> = package_manager
+        .owned_files()?
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+
+    let mut directories = vec![PathBuf::from("/etc")];
+    while let Some(directory) = directories.pop() {
+        // Unreadable directories (ex: /etc/sudoers.d when not root) are skipped.
+        let Ok(entries) = fs::read_dir(&directory) else {
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+
+            if file_type.is_dir() {
+                directories.push(path);
+            } else if file_type.is_file() && !owned_files.contains(&path) {
+                candidates.push(path);
+            }
+        }
+    }
+
+    candidates.sort();
+    candidates.dedup();
+
+    Ok(candidates)
+}
+
+impl SystemSnapshot {
+    /// Capture the current running system. Only the `/etc` files in `files` are included.
+    pub fn capture(package_manager: &PackageManager,
+                   service_manager: &ServiceManager,
+                   files: &[PathBuf]) -> anyhow::Result<Self> {
+        let (users, groups) = users_and_groups()?;
+
+        let timezone = fs::read_link("/etc/localtime")
+            .ok()
+            .and_then(|target| target
+                .to_string_lossy()
+                .split_once("zoneinfo/")
+                .map(|(_, zone)| zone.to_owned()));
+
+        // Bootloaders add their own entries which have nothing to do with the user's settings.
+        let kernel_cmdline = fs::read_to_string("/proc/cmdline")
+            .ok()
+            .map(|cmdline| cmdline
+                .split_whitespace()
+                .filter(|arg| !arg.starts_with("BOOT_IMAGE=") && !arg.starts_with("initrd="))
+                .collect::<Vec<_>>()
+                .join(" "));
+
+        Ok(SystemSnapshot {
+            hostname: fs::read_to_string("/etc/hostname")?.trim().to_owned(),
+            packages: package_manager.explicit_packages()?,
+            services: service_manager.enabled_services()?,
+            users,
+            groups,
+            timezone,
+            locale: read_key_value(Path::new("/etc/locale.conf"), "LANG"),
+            keymap: read_key_value(Path::new("/etc/vconsole.conf"), "KEYMAP"),
+            kernel_cmdline,
+            files: files.to_vec()
+        })
+    }
+
+    /// Write config.lua along with a module per topic into `directory`.
+    ///
+    /// Every module sets its own globals and is listed in the `imports` of config.lua. Selected
+    /// `/etc` files are copied into `files/` keeping their full path.
+    ///
+    /// Unless `overwrite` is set nothing is written if any of these files already exists.
+    pub fn write(&self, directory: &Path, overwrite: bool) -> anyhow::Result<()> {
+        let modules_directory = directory.join("modules");
+
+        let mut modules: BTreeMap<&str, LuaChunk> = BTreeMap::new();
+
+        let mut packages = LuaChunk::new();
+        packages
+            .comment("Explicitly installed packages.\nDependencies are pulled in by the package manager and don't belong here.")
+            .assign("packages", self.packages.clone());
+        modules.insert("packages", packages);
+
+        let mut services = LuaChunk::new();
+        services
+            .comment("Services enabled at boot.")
+            .assign("services", self.services.clone());
+        modules.insert("services", services);
+
+        let mut users_table = LuaTable::new();
+        for user in &self.users {
+            let mut user_table = LuaTable::new();
+            user_table
+                .set("uid", user.uid)
+                .set("home", &user.home)
+                .set("shell", &user.shell)
+                .set_commented("groups", user.groups.clone(), "Supplementary groups, the primary group is left out.");
+            users_table.set(&user.name, user_table);
+        }
+
+        let mut groups_table = LuaTable::new();
+        for group in &self.groups {
+            let mut group_table = LuaTable::new();
+            group_table.set("gid", group.gid);
+            groups_table.set(&group.name, group_table);
+        }
+
+        let mut users = LuaChunk::new();
+        users
+            .comment("Non-system users, the primary group of each user is created along with it.")
+            .assign("users", users_table)
+            .blank()
+            .comment("Non-system groups that aren't the private group of a user.")
+            .assign("groups", groups_table);
+        modules.insert("users", users);
+
+        let mut system = LuaChunk::new();
+        system.comment("Localization and boot settings.");
+        for (comment, name, value) in [
+            ("Relative to /usr/share/zoneinfo", "timezone", &self.timezone),
+            ("Value of LANG in /etc/locale.conf", "locale", &self.locale),
+            ("Console keymap from /etc/vconsole.conf", "keymap", &self.keymap),
+            ("Kernel command line without bootloader entries", "kernel_cmdline", &self.kernel_cmdline)
+        ] {
+            system.blank();
+            match value {
+                Some(value) => system.comment(comment).assign(name, value),
+                None => system.comment(&format!("{} (not found on this system)\n{} = \"\"", comment, name))
+            };
+        }
+        modules.insert("system", system);
+
+        // The file to copy and where it goes.
+        let mut copies = vec![];
+        let mut files_table = LuaTable::new();
+        for file in &self.files {
+            let relative = file.strip_prefix("/").map_err(|_| anyhow!("\"{}\" is not an absolute path", file.display()))?;
+            let source = Path::new("files").join(relative);
+
+            copies.push((file, directory.join(&source)));
+            files_table.set(&file.to_string_lossy(), source.to_string_lossy().to_string());
+        }
+
+        let mut files = LuaChunk::new();
+        files
+            .comment("Files managed by goat, mapping the destination to its source in the configuration directory.")
+            .assign("files", files_table);
+        modules.insert("files", files);
+
+        let mut config = LuaChunk::new();
+        config
+            .comment("Generated by goat from the running system.\n\n\
+                      Each part of the configuration lives in its own module under modules/.\n\
+                      Services, users, system settings and files are recorded for reference,\n\
+                      goat doesn't apply them yet.")
+            .blank()
+            .assign("hostname", &self.hostname)
+            .blank();
+
+        let imports: Vec<String> = modules.keys().map(|name| format!("modules/{}.lua", name)).collect();
+        config.assign("imports", imports);
+
+        let config_file = directory.join("config.lua");
+        let module_files: Vec<PathBuf> = modules.keys().map(|name| modules_directory.join(format!("{}.lua", name))).collect();
+
+        if !overwrite {
+            let existing: Vec<String> = [&config_file]
+                .into_iter()
+                .chain(&module_files)
+                .chain(copies.iter().map(|(_, destination)| destination))
+                .filter(|path| path.exists())
+                .map(|path| format!("\"{}\"", path.display()))
+                .collect();
+
+            if !existing.is_empty() {
+                return Err(anyhow!("{} already exist(s), move them out of the way or overwrite them with `goat import --force`", existing.join(", ")));
+            }
+        }
+
+        fs::create_dir_all(&modules_directory)?;
+        for (module, module_file) in modules.values().zip(&module_files) {
+            fs::write(module_file, module.render())?;
+        }
+
+        for (file, destination) in copies {
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(file, &destination)
+                .map_err(|e| anyhow!("Failed to copy \"{}\": {}", file.display(), e))?;
+        }
+
+        fs::write(config_file, config.render())?;
+
+        Ok(())
+    }
+}
+
+impl Goat {
+    /// Capture the running system and write it as a configuration into `options.output`.
+    /// Nothing is written if config.lua, one of its modules or a copied file already exists,
+    /// unless `options.force` is set.
+    pub fn import(&self, options: &ImportOptions) -> anyhow::Result<()> {
+        let candidates: Vec<PathBuf> = if options.all_etc_files || !options.etc_files.is_empty() {
+            modified_etc_files(&self.package_manager)
+                .map_err(|e| anyhow!("Can't import /etc files: {}", e))?
+                .into_iter()
+                .filter(|file| options.all_etc_files || options.etc_files.iter().any(|selected| file.starts_with(selected)))
+                .collect()
+        } else {
+            vec![]
+        };
+
+        for selected in &options.etc_files {
+            if !candidates.iter().any(|file| file.starts_with(selected)) {
+                log::warn!("\"{}\" isn't a modified /etc file, skipping.", selected.display());
+            }
+        }
+
+        // The configuration tends to end up in a git repository, secrets only go in on request.
+        let mut files = vec![];
+        for file in candidates {
+            if (is_secret(&file) || !is_world_readable(&file)) && !options.etc_files.contains(&file) {
+                log::info!("\"{}\" might hold secrets, skipping. Pass it with --etc {} to include it.", file.display(), file.display());
+                continue;
+            }
+
+            files.push(file);
+        }
+
+        let snapshot = SystemSnapshot::capture(&self.package_manager, &self.service_manager, &files)?;
+        snapshot.write(&options.output, options.force)?;
+
+        log::info!(
+            "Imported {} package(s), {} service(s), {} user(s), {} group(s) and {} file(s) into \"{}\"",
+            snapshot.packages.len(),
+            snapshot.services.len(),
+            snapshot.users.len(),
+            snapshot.groups.len(),
+            snapshot.files.len(),
+            options.output.display()
+        );
+
+        Ok(())
+    }
+}
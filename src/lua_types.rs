@@ -0,0 +1,148 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::config::{Config, PackagePin, Repository};
+use crate::goat::Goat;
+use crate::hooks::Hooks;
+use crate::package_manager::PackageManager;
+use crate::service_manager::ServiceManager;
+// lua_types.rs
+//
+// All logic related to the `lsp-types` subcommand should be placed here.
+
+/// Lua language server (LuaLS) definitions of a type, derived with `goat_lua_macro::LuaTypes`.
+pub trait LuaTypes {
+    fn lua_types() -> &'static str;
+}
+
+/// Globals `Config::from_table` and `Config::evaluate` read that have no field of their own.
+const CONFIG_EXTRA_GLOBALS: &str = r#"
+---Lua files merged into the configuration before it, relative to the configuration directory.
+---@type string[]
+imports = nil
+
+---Named sets of configuration (packages, repositories, ...) enabled with `profiles`.
+---@type table<string, table>
+roles = nil
+
+---Packages per install command with `install_strategy = "batches"`.
+---@type integer
+install_batch_size = nil
+"#;
+
+/// Types the `hooks` fields refer to, see `hooks.rs`.
+const HOOK_TYPES: &str = r#"
+---A shell command, run with `GOAT_HOOK`, `GOAT_HOSTNAME`, `GOAT_STAGE`, `GOAT_RESULT` and
+---`GOAT_ERROR` set, or a function called with the same values.
+---@alias goat.Hook string|fun(ctx: goat.HookContext)
+
+---@class goat.HookContext
+---@field hook "pre_sync"|"post_sync"|"before"|"after"|"on_failure"
+---@field hostname string
+---@field stage string?
+---@field result "done"|"skipped"|"failed"?
+---@field error string?
+"#;
+
+/// Custom stage files, see `CustomStage` and `Goat::with_stage_context`.
+const CUSTOM_STAGE_TYPES: &str = r#"
+---@class goat.StageContext
+---@field config table The evaluated configuration, as `goat eval --json` shows it.
+---@field hostname string
+---@field directories table<string, string> goat's directories by name.
+---@field packages goat.StagePackages
+---@field run (fun(command: string): stdout: string, stderr: string)? Raises an error if the command fails. Not available in `stage.diff`.
+---@field log goat.StageLog
+
+---@class goat.StagePackages
+---@field install (fun(packages: string[]): string[])? Install packages, returns the ones that were installed. Not available in `stage.diff`.
+---@field installed fun(): string[] Every installed package.
+
+---@class goat.StageLog
+---@field info fun(message: string)
+---@field warn fun(message: string)
+---@field error fun(message: string)
+
+---@class goat.StageDiff
+---@field missing string[]?
+---@field extra string[]?
+---@field changed { item: string, current: string, expected: string }[]?
+
+---@class goat.Stage
+---@field apply fun(ctx: goat.StageContext)
+---@field diff (fun(ctx: goat.StageContext): goat.StageDiff)?
+---@field after string[]? Stages that have to be applied before this one.
+---@field before string[]? Stages that have to be applied after this one.
+---@field parallel boolean? Whether the stage can be applied at the same time as other stages.
+
+---@type goat.Stage
+stage = nil
+"#;
+
+/// Name of the configuration file editors read the library directories from.
+const LUARC_FILE: &str = ".luarc.json";
+
+impl Goat {
+    /// Every definition file as `(library, contents)`. Each library is written to its own
+    /// directory so editors only load the ones that apply to a file.
+    pub fn lua_type_definitions() -> Vec<(&'static str, String)> {
+        let header = |name: &str| format!("---@meta {}\n-- Generated by `goat lsp-types`, do not edit.\n", name);
+
+        // Globals start with a blank line of their own, classes don't.
+        vec![
+            ("goat", format!("{}\n{}", header("goat"), goat_lua::goat_lua_types())),
+            ("config", format!("{}\n{}\n{}\n{}{}\n{}{}",
+                header("config"),
+                PackagePin::lua_types(),
+                Repository::lua_types(),
+                Hooks::lua_types(),
+                HOOK_TYPES,
+                Config::lua_types(),
+                CONFIG_EXTRA_GLOBALS)),
+            ("package_manager", format!("{}{}", header("package_manager"), PackageManager::lua_types())),
+            ("service_manager", format!("{}{}", header("service_manager"), ServiceManager::lua_types())),
+            ("custom_stage", format!("{}{}", header("custom_stage"), CUSTOM_STAGE_TYPES))
+        ]
+    }
+
+    /// Write the definitions into `output` and point editors in goat's lua directories at them.
+    ///
+    /// A `.luarc.json` is only written into directories that don't have one yet.
+    pub fn write_lua_types(&self, output: &Path) -> anyhow::Result<()> {
+        for (library, contents) in Self::lua_type_definitions() {
+            let directory = output.join(library);
+            fs::create_dir_all(&directory)?;
+            fs::write(directory.join(format!("{}.lua", library)), contents)?;
+        }
+
+        log::info!("Wrote lua definitions to \"{}\"", output.display());
+
+        // Editors resolve the libraries relative to the workspace otherwise.
+        let output = fs::canonicalize(output)?;
+
+        let workspaces = [
+            ("configuration_directory", "config"),
+            ("package_manager_configuration_directory", "package_manager"),
+            ("service_manager_configuration_directory", "service_manager"),
+            ("custom_stages", "custom_stage")
+        ];
+
+        for (directory, library) in workspaces {
+            let luarc_file = self.directories[directory].join(LUARC_FILE);
+            if luarc_file.exists() {
+                log::info!("\"{}\" already exists, leaving it alone", luarc_file.display());
+                continue;
+            }
+
+            let libraries: Vec<PathBuf> = vec![output.join("goat"), output.join(library)];
+            let luarc = serde_json::json!({
+                "runtime.version": "Lua 5.4",
+                "workspace.library": libraries
+            });
+
+            fs::write(&luarc_file, format!("{}\n", serde_json::to_string_pretty(&luarc)?))?;
+            log::info!("Wrote \"{}\"", luarc_file.display());
+        }
+
+        Ok(())
+    }
+}
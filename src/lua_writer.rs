@@ -0,0 +1,1039 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use anyhow::anyhow;
+
+// lua_writer.rs
+//
+// Everything that writes lua source lives here. `LuaValue` and `LuaChunk` generate new files while
+// `LuaSource` edits existing ones in place so user comments and formatting survive.
+
+/// Words that can't be used as bare table keys.
+const KEYWORDS: [&str; 22] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while"
+];
+
+const INDENT: &str = "    ";
+
+/// A lua value that can be written as lua source.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LuaValue {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    Table(LuaTable)
+}
+
+/// A lua table. The sequence part is written first in order, keyed fields follow sorted by key.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct LuaTable {
+    pub array: Vec<LuaValue>,
+    pub fields: BTreeMap<String, LuaValue>,
+
+    /// Comments written above keyed fields, may span several lines.
+    pub comments: BTreeMap<String, String>
+}
+
+impl LuaTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a keyed field.
+    pub fn set(&mut self, key: &str, value: impl Into<LuaValue>) -> &mut Self {
+        self.fields.insert(key.to_owned(), value.into());
+        self
+    }
+
+    /// Set a keyed field with a comment above it.
+    pub fn set_commented(&mut self, key: &str, value: impl Into<LuaValue>, comment: &str) -> &mut Self {
+        self.comments.insert(key.to_owned(), comment.to_owned());
+        self.set(key, value)
+    }
+}
+
+impl From<bool> for LuaValue {
+    fn from(value: bool) -> Self { LuaValue::Boolean(value) }
+}
+
+impl From<i64> for LuaValue {
+    fn from(value: i64) -> Self { LuaValue::Integer(value) }
+}
+
+impl From<u32> for LuaValue {
+    fn from(value: u32) -> Self { LuaValue::Integer(value.into()) }
+}
+
+impl From<f64> for LuaValue {
+    fn from(value: f64) -> Self { LuaValue::Number(value) }
+}
+
+impl From<&str> for LuaValue {
+    fn from(value: &str) -> Self { LuaValue::String(value.to_owned()) }
+}
+
+impl From<String> for LuaValue {
+    fn from(value: String) -> Self { LuaValue::String(value) }
+}
+
+impl From<&String> for LuaValue {
+    fn from(value: &String) -> Self { LuaValue::String(value.clone()) }
+}
+
+impl From<LuaTable> for LuaValue {
+    fn from(value: LuaTable) -> Self { LuaValue::Table(value) }
+}
+
+impl<T: Into<LuaValue>> From<Option<T>> for LuaValue {
+    fn from(value: Option<T>) -> Self {
+        value.map(Into::into).unwrap_or(LuaValue::Nil)
+    }
+}
+
+impl<T: Into<LuaValue>> From<Vec<T>> for LuaValue {
+    fn from(values: Vec<T>) -> Self {
+        LuaValue::Table(LuaTable {
+            array: values.into_iter().map(Into::into).collect(),
+            ..LuaTable::default()
+        })
+    }
+}
+
+/// Quote and escape a string so lua reads back exactly `value`.
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+
+    for character in value.chars() {
+        match character {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            // Zero padded so a following digit isn't read as part of the escape.
+            c if c.is_control() && c.is_ascii() => quoted.push_str(&format!("\\{:03}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// Returns true if `key` can be written as `key = value` instead of `["key"] = value`.
+pub fn is_identifier(key: &str) -> bool {
+    let mut characters = key.chars();
+
+    match characters.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {},
+        _ => return false
+    }
+
+    characters.all(|c| c.is_ascii_alphanumeric() || c == '_') && !KEYWORDS.contains(&key)
+}
+
+/// Write a comment with `--` in front of each of its lines.
+fn write_comment(out: &mut String, comment: &str, indent: &str) {
+    for line in comment.lines() {
+        if line.is_empty() {
+            out.push_str(&format!("{}--\n", indent));
+        } else {
+            out.push_str(&format!("{}-- {}\n", indent, line));
+        }
+    }
+}
+
+/// Tables nested deeper than this are cut off when converting evaluated values, they are most
+/// likely a reference cycle.
+const MAX_DEPTH: usize = 32;
+
+impl LuaValue {
+    /// Convert a value from an evaluated lua state. Functions, userdata and threads can't be
+    /// written as source and become nil, as do keys that aren't strings or part of the sequence.
+    pub fn from_lua_value(value: &mlua::Value) -> Self {
+        Self::from_lua_value_at(value, 0)
+    }
+
+    fn from_lua_value_at(value: &mlua::Value, depth: usize) -> Self {
+        match value {
+            mlua::Value::Boolean(value) => LuaValue::Boolean(*value),
+            mlua::Value::Integer(value) => LuaValue::Integer(*value),
+            mlua::Value::Number(value) => LuaValue::Number(*value),
+            mlua::Value::String(value) => LuaValue::String(value.to_string_lossy()),
+            mlua::Value::Table(table) if depth < MAX_DEPTH => {
+                let mut result = LuaTable::new();
+                let length = table.raw_len();
+
+                for pair in table.pairs::<mlua::Value, mlua::Value>().flatten() {
+                    let value = Self::from_lua_value_at(&pair.1, depth + 1);
+                    if value == LuaValue::Nil {
+                        continue;
+                    }
+
+                    match pair.0 {
+                        mlua::Value::Integer(index) if index >= 1 && (index as usize) <= length => {},
+                        mlua::Value::String(key) => { result.set(&key.to_string_lossy(), value); },
+                        _ => {}
+                    }
+                }
+
+                result.array = (1..=length)
+                    .map(|index| table.raw_get::<mlua::Value>(index).map(|value| Self::from_lua_value_at(&value, depth + 1)))
+                    .collect::<Result<_, _>>()
+                    .unwrap_or_default();
+
+                LuaValue::Table(result)
+            },
+            _ => LuaValue::Nil
+        }
+    }
+
+    /// Lua source for this value. Nested tables are indented by four spaces per level.
+    pub fn to_lua(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, 0);
+        out
+    }
+
+    fn write(&self, out: &mut String, depth: usize) {
+        match self {
+            LuaValue::Nil => out.push_str("nil"),
+            LuaValue::Boolean(value) => out.push_str(if *value { "true" } else { "false" }),
+            LuaValue::Integer(value) => out.push_str(&value.to_string()),
+            LuaValue::Number(value) => {
+                if value.is_nan() {
+                    out.push_str("(0/0)");
+                } else if value.is_infinite() {
+                    out.push_str(if *value > 0.0 { "math.huge" } else { "-math.huge" });
+                } else if value.fract() == 0.0 && value.abs() < 1e15 {
+                    // Keep the float subtype when read back.
+                    out.push_str(&format!("{:.1}", value));
+                } else {
+                    out.push_str(&value.to_string());
+                }
+            },
+            LuaValue::String(value) => out.push_str(&quote(value)),
+            LuaValue::Table(table) => {
+                if table.array.is_empty() && table.fields.is_empty() {
+                    out.push_str("{}");
+                    return;
+                }
+
+                let indent = INDENT.repeat(depth + 1);
+                out.push_str("{\n");
+
+                for value in &table.array {
+                    out.push_str(&indent);
+                    value.write(out, depth + 1);
+                    out.push_str(",\n");
+                }
+
+                for (key, value) in &table.fields {
+                    if let Some(comment) = table.comments.get(key) {
+                        write_comment(out, comment, &indent);
+                    }
+
+                    if is_identifier(key) {
+                        out.push_str(&format!("{}{} = ", indent, key));
+                    } else {
+                        out.push_str(&format!("{}[{}] = ", indent, quote(key)));
+                    }
+
+                    value.write(out, depth + 1);
+                    out.push_str(",\n");
+                }
+
+                out.push_str(&INDENT.repeat(depth));
+                out.push('}');
+            }
+        }
+    }
+}
+
+/// A whole lua file built statement by statement.
+#[derive(Default)]
+pub struct LuaChunk {
+    source: String
+}
+
+impl LuaChunk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a comment, each line of `comment` gets its own `--`.
+    pub fn comment(&mut self, comment: &str) -> &mut Self {
+        write_comment(&mut self.source, comment, "");
+        self
+    }
+
+    /// Add a global assignment.
+    pub fn assign(&mut self, name: &str, value: impl Into<LuaValue>) -> &mut Self {
+        self.source.push_str(&format!("{} = {}\n", name, value.into().to_lua()));
+        self
+    }
+
+    pub fn blank(&mut self) -> &mut Self {
+        self.source.push('\n');
+        self
+    }
+
+    pub fn render(&self) -> &str {
+        &self.source
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TokenKind {
+    Name,
+    String,
+    Number,
+    Comment,
+    Symbol
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Token {
+    kind: TokenKind,
+    start: usize,
+    end: usize
+}
+
+/// If a long bracket (`[[`, `[==[`, ...) starts at `index` return its level.
+fn long_bracket_level(bytes: &[u8], index: usize) -> Option<usize> {
+    if bytes.get(index) != Some(&b'[') {
+        return None;
+    }
+
+    let level = bytes[index + 1..].iter().take_while(|byte| **byte == b'=').count();
+    (bytes.get(index + 1 + level) == Some(&b'[')).then_some(level)
+}
+
+/// Return the index right after the long bracket starting at `index` is closed.
+fn skip_long_bracket(bytes: &[u8], index: usize, level: usize) -> anyhow::Result<usize> {
+    let closing = format!("]{}]", "=".repeat(level));
+    let body = index + level + 2;
+
+    bytes[body..]
+        .windows(closing.len())
+        .position(|window| window == closing.as_bytes())
+        .map(|position| body + position + closing.len())
+        .ok_or_else(|| anyhow!("Unfinished long string or comment"))
+}
+
+/// Split lua source into tokens. Whitespace is dropped, comments are kept.
+fn tokenize(source: &str) -> anyhow::Result<Vec<Token>> {
+    const SYMBOLS: [&str; 10] = ["...", "..", "==", "~=", "<=", ">=", "//", "::", "<<", ">>"];
+
+    let bytes = source.as_bytes();
+    let mut tokens = vec![];
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let byte = bytes[index];
+        let start = index;
+
+        if byte.is_ascii_whitespace() {
+            index += 1;
+            continue;
+        }
+
+        let kind = if source[index..].starts_with("--") {
+            index += 2;
+            match long_bracket_level(bytes, index) {
+                Some(level) => index = skip_long_bracket(bytes, index, level)?,
+                None => while index < bytes.len() && bytes[index] != b'\n' { index += 1 }
+            }
+            TokenKind::Comment
+        } else if let Some(level) = long_bracket_level(bytes, index) {
+            index = skip_long_bracket(bytes, index, level)?;
+            TokenKind::String
+        } else if byte == b'"' || byte == b'\'' {
+            index += 1;
+            while index < bytes.len() && bytes[index] != byte {
+                match bytes[index] {
+                    b'\\' if bytes.get(index + 1) == Some(&b'z') => {
+                        index += 2;
+                        while index < bytes.len() && bytes[index].is_ascii_whitespace() { index += 1 }
+                        continue;
+                    },
+                    b'\\' => index += 1,
+                    b'\n' => return Err(anyhow!("Unfinished string on line {}", line_number(source, start))),
+                    _ => {}
+                }
+                index += 1;
+            }
+
+            if index >= bytes.len() {
+                return Err(anyhow!("Unfinished string on line {}", line_number(source, start)));
+            }
+            index += 1;
+            TokenKind::String
+        } else if byte.is_ascii_alphabetic() || byte == b'_' {
+            while index < bytes.len() && (bytes[index].is_ascii_alphanumeric() || bytes[index] == b'_') { index += 1 }
+            TokenKind::Name
+        } else if byte.is_ascii_digit() || (byte == b'.' && bytes.get(index + 1).is_some_and(u8::is_ascii_digit)) {
+            while index < bytes.len() {
+                let current = bytes[index];
+                if matches!(current, b'e' | b'E' | b'p' | b'P') && matches!(bytes.get(index + 1), Some(b'+' | b'-')) {
+                    index += 2;
+                } else if current.is_ascii_alphanumeric() || current == b'.' {
+                    index += 1;
+                } else {
+                    break;
+                }
+            }
+            TokenKind::Number
+        } else {
+            index += SYMBOLS
+                .iter()
+                .find(|symbol| source[index..].starts_with(*symbol))
+                .map(|symbol| symbol.len())
+                .unwrap_or_else(|| source[index..].chars().next().map(char::len_utf8).unwrap_or(1));
+            TokenKind::Symbol
+        };
+
+        tokens.push(Token { kind, start, end: index });
+    }
+
+    Ok(tokens)
+}
+
+fn line_number(source: &str, index: usize) -> usize {
+    source[..index].matches('\n').count() + 1
+}
+
+/// Read the value of a string token.
+fn string_value(text: &str) -> Option<String> {
+    if let Some(rest) = text.strip_prefix('[') {
+        let level = rest.chars().take_while(|c| *c == '=').count();
+        let body = &text[level + 2..text.len() - level - 2];
+        // A newline right after the opening bracket isn't part of the string.
+        let body = body.strip_prefix("\r\n").or_else(|| body.strip_prefix('\n')).unwrap_or(body);
+        return Some(body.to_owned());
+    }
+
+    let mut value = String::new();
+    let mut characters = text[1..text.len() - 1].chars().peekable();
+
+    while let Some(character) = characters.next() {
+        if character != '\\' {
+            value.push(character);
+            continue;
+        }
+
+        match characters.next()? {
+            'a' => value.push('\x07'),
+            'b' => value.push('\x08'),
+            'f' => value.push('\x0c'),
+            'n' | '\n' => value.push('\n'),
+            'r' => value.push('\r'),
+            't' => value.push('\t'),
+            'v' => value.push('\x0b'),
+            'z' => while characters.peek().is_some_and(|c| c.is_whitespace()) { characters.next(); },
+            'x' => {
+                let hex: String = [characters.next()?, characters.next()?].iter().collect();
+                value.push(char::from(u8::from_str_radix(&hex, 16).ok()?));
+            },
+            'u' => {
+                if characters.next()? != '{' {
+                    return None;
+                }
+                let hex: String = characters.by_ref().take_while(|c| *c != '}').collect();
+                value.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+            },
+            digit if digit.is_ascii_digit() => {
+                let mut decimal = digit.to_string();
+                while decimal.len() < 3 && characters.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    decimal.push(characters.next()?);
+                }
+                value.push(char::from(decimal.parse::<u8>().ok()?));
+            },
+            other => value.push(other)
+        }
+    }
+
+    Some(value)
+}
+
+/// A string entry of a table constructor, or a table entry with a `name` such as a pinned package.
+struct ListEntry {
+    value: String,
+    /// Token index of the string, or of the table's opening brace.
+    token: usize,
+    /// Token index of the string, or of the table's closing brace.
+    last: usize
+}
+
+/// Lua source that can be edited while keeping everything that isn't touched (comments,
+/// formatting, ordering) exactly the way it was.
+///
+/// Only top level global assignments such as `packages = { ... }` can be edited.
+pub struct LuaSource {
+    source: String,
+    tokens: Vec<Token>
+}
+
+impl LuaSource {
+    pub fn parse(source: String) -> anyhow::Result<Self> {
+        let tokens = tokenize(&source)?;
+        Ok(Self { source, tokens })
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::parse(fs::read_to_string(path)?)
+            .map_err(|e| anyhow!("Failed to parse \"{}\": {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        Ok(fs::write(path, &self.source)?)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    fn text(&self, token: usize) -> &str {
+        &self.source[self.tokens[token].start..self.tokens[token].end]
+    }
+
+    fn is_symbol(&self, token: usize, symbol: &str) -> bool {
+        self.tokens[token].kind == TokenKind::Symbol && self.text(token) == symbol
+    }
+
+    /// Replace `start..end` of the source and tokenize again.
+    fn splice(&mut self, start: usize, end: usize, replacement: &str) -> anyhow::Result<()> {
+        let mut source = self.source.clone();
+        source.replace_range(start..end, replacement);
+        *self = Self::parse(source)?;
+        Ok(())
+    }
+
+    /// Indices of every token that isn't a comment.
+    fn code(&self) -> Vec<usize> {
+        (0..self.tokens.len()).filter(|index| self.tokens[*index].kind != TokenKind::Comment).collect()
+    }
+
+    /// Token index of the closing bracket matching the opening bracket at `open`.
+    fn matching_bracket(&self, code: &[usize], open: usize) -> anyhow::Result<usize> {
+        let mut depth = 0;
+        for &index in code.iter().skip_while(|index| **index != open) {
+            match self.text(index) {
+                "{" | "(" | "[" if self.tokens[index].kind == TokenKind::Symbol => depth += 1,
+                "}" | ")" | "]" if self.tokens[index].kind == TokenKind::Symbol => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(index);
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        Err(anyhow!("Unbalanced brackets starting on line {}", line_number(&self.source, self.tokens[open].start)))
+    }
+
+    /// Find the last top level assignment to the global `name` and return the first and last token
+    /// of its value. `None` if the global isn't assigned at the top level.
+    fn find_global(&self, name: &str) -> anyhow::Result<Option<(usize, usize)>> {
+        let code = self.code();
+        let mut found = None;
+        let mut block_depth: i32 = 0;
+        let mut bracket_depth: i32 = 0;
+
+        for (position, &index) in code.iter().enumerate() {
+            let token = self.tokens[index];
+            let text = self.text(index);
+
+            match (token.kind, text) {
+                (TokenKind::Symbol, "{" | "(" | "[") => bracket_depth += 1,
+                (TokenKind::Symbol, "}" | ")" | "]") => bracket_depth -= 1,
+                (TokenKind::Name, "function" | "if" | "do" | "repeat") => block_depth += 1,
+                (TokenKind::Name, "end" | "until") => block_depth -= 1,
+                _ => {}
+            }
+
+            if token.kind != TokenKind::Name || text != name || block_depth != 0 || bracket_depth != 0 {
+                continue;
+            }
+
+            let previous = position.checked_sub(1).map(|previous| self.text(code[previous]));
+            if matches!(previous, Some("." | ":" | "local" | ",")) {
+                continue;
+            }
+
+            let (Some(&equals), Some(&value)) = (code.get(position + 1), code.get(position + 2)) else {
+                continue;
+            };
+
+            if !self.is_symbol(equals, "=") {
+                continue;
+            }
+
+            let end = if self.is_symbol(value, "{") {
+                self.matching_bracket(&code, value)?
+            } else {
+                value
+            };
+
+            // Anything that continues the expression (`..`, calls, indexing) means we can't tell
+            // where the value ends without a full parser.
+            let simple = self.is_symbol(value, "{")
+                || matches!(self.tokens[value].kind, TokenKind::String | TokenKind::Number)
+                || matches!(self.text(value), "true" | "false" | "nil");
+            let continued = code
+                .iter()
+                .skip_while(|index| **index != end)
+                .nth(1)
+                .is_some_and(|next| (self.tokens[*next].kind == TokenKind::Symbol && self.text(*next) != ";")
+                    || matches!(self.text(*next), "and" | "or"));
+
+            found = if simple && !continued {
+                Some(Ok((value, end)))
+            } else {
+                Some(Err(anyhow!(
+                    "\"{}\" on line {} isn't a plain value, it has to be edited by hand",
+                    name,
+                    line_number(&self.source, token.start)
+                )))
+            };
+        }
+
+        found.transpose()
+    }
+
+    /// Returns true if `name` is assigned at the top level, even if the value is too complex to be
+    /// edited.
+    pub fn has_global(&self, name: &str) -> bool {
+        matches!(self.find_global(name), Ok(Some(_)) | Err(_))
+    }
+
+    /// Set the global `name` to `value`. An existing top level assignment is replaced in place,
+    /// otherwise the assignment is appended to the end of the file.
+    pub fn set_global(&mut self, name: &str, value: &LuaValue) -> anyhow::Result<()> {
+        match self.find_global(name)? {
+            Some((first, last)) => {
+                let (start, end) = (self.tokens[first].start, self.tokens[last].end);
+                self.splice(start, end, &value.to_lua())
+            },
+            None => {
+                let mut addition = String::new();
+                if !self.source.is_empty() && !self.source.ends_with('\n') {
+                    addition.push('\n');
+                }
+                addition.push_str(&format!("{} = {}\n", name, value.to_lua()));
+
+                let end = self.source.len();
+                self.splice(end, end, &addition)
+            }
+        }
+    }
+
+    /// The table constructor assigned to `name`, as the token indices of its braces.
+    fn find_table(&self, name: &str) -> anyhow::Result<Option<(usize, usize)>> {
+        match self.find_global(name)? {
+            Some((open, close)) if self.is_symbol(open, "{") => Ok(Some((open, close))),
+            Some((open, _)) => Err(anyhow!(
+                "\"{}\" on line {} isn't a table",
+                name,
+                line_number(&self.source, self.tokens[open].start)
+            )),
+            None => Ok(None)
+        }
+    }
+
+    /// The plain string `name` field of the table constructor made of the tokens `entry`, like
+    /// `{ name = "linux-zen", version = "6.9.1" }`.
+    fn table_name(&self, entry: &[usize]) -> Option<String> {
+        let (&first, &last) = (entry.first()?, entry.last()?);
+        if !self.is_symbol(first, "{") || !self.is_symbol(last, "}") {
+            return None;
+        }
+
+        let mut depth = 0;
+        let mut name = None;
+
+        for (position, &index) in entry.iter().enumerate() {
+            if self.tokens[index].kind == TokenKind::Symbol {
+                match self.text(index) {
+                    "{" | "(" | "[" => depth += 1,
+                    "}" | ")" | "]" => depth -= 1,
+                    _ => {}
+                }
+            }
+
+            // Something like `{ ... } .. { ... }`, not a single table.
+            if depth == 0 && index != last {
+                return None;
+            }
+
+            if depth == 1 && self.tokens[index].kind == TokenKind::Name && self.text(index) == "name"
+                && let [equals, value, separator] = entry.get(position + 1..position + 4)?
+                && self.is_symbol(*equals, "=")
+                && self.tokens[*value].kind == TokenKind::String
+                && [",", ";", "}"].iter().any(|symbol| self.is_symbol(*separator, symbol)) {
+                name = string_value(self.text(*value));
+            }
+        }
+
+        name
+    }
+
+    /// Every plain string entry of the table between the braces `open` and `close`, along with
+    /// every table entry that has a plain string `name`.
+    fn list_entries(&self, open: usize, close: usize) -> Vec<ListEntry> {
+        let code: Vec<usize> = self.code().into_iter().filter(|index| *index > open && *index < close).collect();
+        let mut entries = vec![];
+        let mut current: Vec<usize> = vec![];
+        let mut depth = 0;
+
+        for index in code.into_iter().chain(std::iter::once(close)) {
+            let text = self.text(index);
+            let separator = index == close || (depth == 0 && (self.is_symbol(index, ",") || self.is_symbol(index, ";")));
+
+            if separator {
+                if let [token] = current[..] && self.tokens[token].kind == TokenKind::String
+                    && let Some(value) = string_value(self.text(token)) {
+                    entries.push(ListEntry { value, token, last: token });
+                } else if let Some(value) = self.table_name(&current) {
+                    entries.push(ListEntry { value, token: current[0], last: current[current.len() - 1] });
+                }
+                current.clear();
+                continue;
+            }
+
+            if self.tokens[index].kind == TokenKind::Symbol {
+                match text {
+                    "{" | "(" | "[" => depth += 1,
+                    "}" | ")" | "]" => depth -= 1,
+                    _ => {}
+                }
+            }
+            current.push(index);
+        }
+
+        entries
+    }
+
+    /// Start of the line containing `index`.
+    fn line_start(&self, index: usize) -> usize {
+        self.source[..index].rfind('\n').map(|position| position + 1).unwrap_or(0)
+    }
+
+    /// Index right after the newline ending the line containing `index`.
+    fn line_end(&self, index: usize) -> usize {
+        self.source[index..].find('\n').map(|position| index + position + 1).unwrap_or(self.source.len())
+    }
+
+    /// The token right after `token` that isn't a comment, if it's a `,` or `;`.
+    fn separator_after(&self, token: usize) -> Option<usize> {
+        (token + 1..self.tokens.len())
+            .find(|index| self.tokens[*index].kind != TokenKind::Comment)
+            .filter(|index| self.is_symbol(*index, ",") || self.is_symbol(*index, ";"))
+    }
+
+    /// Every plain string entry of the table assigned to `name` and the `name` of every table
+    /// entry, empty if the global isn't assigned.
+    pub fn list_values(&self, name: &str) -> anyhow::Result<Vec<String>> {
+        Ok(match self.find_table(name)? {
+            Some((open, close)) => self.list_entries(open, close).into_iter().map(|entry| entry.value).collect(),
+            None => vec![]
+        })
+    }
+
+    /// Add the string `value` to the table assigned to `name`, creating the global if needed.
+    ///
+    /// When the existing entries are sorted the new entry is inserted in order, otherwise it is
+    /// appended. Returns false if the value was already present.
+    pub fn list_insert(&mut self, name: &str, value: &str) -> anyhow::Result<bool> {
+        let Some((open, close)) = self.find_table(name)? else {
+            self.set_global(name, &LuaValue::from(vec![value]))?;
+            return Ok(true);
+        };
+
+        let entries = self.list_entries(open, close);
+        if entries.iter().any(|entry| entry.value == value) {
+            return Ok(false);
+        }
+
+        let quoted = quote(value);
+
+        let Some(last) = entries.last() else {
+            // Empty table, anything that was inside (comments) stays after the new entry.
+            let base_indent = self.indentation(open);
+            let start = self.tokens[open].end;
+            let end = self.tokens[close].start;
+
+            if self.source[start..end].trim().is_empty() {
+                self.splice(start, end, &format!("\n{}{}{},\n{}", base_indent, INDENT, quoted, base_indent))?;
+            } else {
+                self.splice(start, start, &format!("\n{}{}{},", base_indent, INDENT, quoted))?;
+            }
+            return Ok(true);
+        };
+
+        let sorted = entries.windows(2).all(|pair| pair[0].value <= pair[1].value);
+        let before = if sorted { entries.iter().find(|entry| entry.value.as_str() > value) } else { None };
+        let multiline = self.on_own_line(entries.first().map(|entry| entry.token).unwrap_or(last.token));
+
+        match (before, multiline) {
+            (Some(entry), true) => {
+                let line = self.line_start(self.tokens[entry.token].start);
+                let indent = self.indentation(entry.token);
+                self.splice(line, line, &format!("{}{},\n", indent, quoted))?;
+            },
+            (Some(entry), false) => {
+                let start = self.tokens[entry.token].start;
+                self.splice(start, start, &format!("{}, ", quoted))?;
+            },
+            (None, true) => {
+                let indent = self.indentation(last.token);
+                let last_token = last.last;
+                match self.separator_after(last_token) {
+                    Some(separator) => {
+                        let line = self.line_end(self.tokens[separator].end);
+                        self.splice(line, line, &format!("{}{},\n", indent, quoted))?;
+                    },
+                    None => {
+                        // Keep the style of the file, a missing trailing comma stays missing.
+                        let line = self.line_end(self.tokens[last_token].end);
+                        self.splice(line, line, &format!("{}{}\n", indent, quoted))?;
+                        let end = self.tokens[last_token].end;
+                        self.splice(end, end, ",")?;
+                    }
+                }
+            },
+            (None, false) => {
+                match self.separator_after(last.last) {
+                    Some(separator) => {
+                        let end = self.tokens[separator].end;
+                        self.splice(end, end, &format!(" {},", quoted))?;
+                    },
+                    None => {
+                        let end = self.tokens[last.last].end;
+                        self.splice(end, end, &format!(", {}", quoted))?;
+                    }
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Remove the string `value`, or the table entry named `value`, from the table assigned to
+    /// `name`.
+    ///
+    /// If the entry sits on its own line the whole line goes, including a trailing comment.
+    /// Returns false if the value wasn't present.
+    pub fn list_remove(&mut self, name: &str, value: &str) -> anyhow::Result<bool> {
+        let Some((open, close)) = self.find_table(name)? else {
+            return Ok(false);
+        };
+
+        let Some(entry) = self.list_entries(open, close).into_iter().find(|entry| entry.value == value) else {
+            return Ok(false);
+        };
+
+        let token = self.tokens[entry.token];
+        let last = self.tokens[entry.last];
+        let separator = self.separator_after(entry.last);
+        let end = separator.map(|separator| self.tokens[separator].end).unwrap_or(last.end);
+
+        let line_start = self.line_start(token.start);
+        let line_end = self.line_end(end);
+        let rest_of_line = self.source[end..line_end].trim();
+
+        if self.on_own_line(entry.token) && (rest_of_line.is_empty() || rest_of_line.starts_with("--")) {
+            self.splice(line_start, line_end, "")?;
+        } else if separator.is_some() {
+            let after = end + self.source[end..].len() - self.source[end..].trim_start_matches([' ', '\t']).len();
+            self.splice(token.start, after, "")?;
+        } else {
+            // Last entry without a trailing comma, take the comma in front of it instead.
+            let before = self.source[..token.start].trim_end().trim_end_matches([',', ';']).trim_end().len();
+            let start = if before < self.tokens[open].end { self.tokens[open].end } else { before };
+            self.splice(start, last.end, "")?;
+        }
+
+        Ok(true)
+    }
+
+    /// Returns true if nothing but whitespace comes before `token` on its line.
+    fn on_own_line(&self, token: usize) -> bool {
+        let start = self.tokens[token].start;
+        self.source[self.line_start(start)..start].trim().is_empty()
+    }
+
+    /// The leading whitespace of the line containing `token`.
+    fn indentation(&self, token: usize) -> String {
+        let start = self.line_start(self.tokens[token].start);
+        self.source[start..]
+            .chars()
+            .take_while(|c| *c == ' ' || *c == '\t')
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluate `source` and return the global `name` as lua sees it.
+    fn evaluate<T: mlua::FromLua>(source: &str, name: &str) -> T {
+        let lua = mlua::Lua::new();
+        lua.load(source).exec().expect("generated lua doesn't run");
+        lua.globals().get(name).expect("global has the wrong type")
+    }
+
+    #[test]
+    fn quoted_strings_read_back_unchanged() {
+        for value in ["plain", "with \"quotes\"", "back\\slash", "new\nline", "tab\tand\0nul", "} --[[ not a comment", "ünïcode"] {
+            let source = format!("value = {}", quote(value));
+            assert_eq!(evaluate::<String>(&source, "value"), value, "{}", source);
+        }
+    }
+
+    #[test]
+    fn nested_tables_round_trip() {
+        let mut inner = LuaTable::new();
+        inner.set("end", "keyword key").set("with space", 1i64).set_commented("flag", true, "A comment\nover two lines");
+
+        let mut table = LuaTable::new();
+        table.array = vec!["first".into(), "} --".into()];
+        table.set("inner", inner).set("number", 1.0).set("empty", LuaTable::new());
+
+        let source = format!("value = {}", LuaValue::from(table).to_lua());
+        assert!(source.contains("        -- A comment\n        -- over two lines\n        flag = true,"), "{}", source);
+        assert!(source.contains("[\"end\"] = "), "{}", source);
+
+        let lua = mlua::Lua::new();
+        lua.load(&source).exec().unwrap();
+        let value: mlua::Table = lua.globals().get("value").unwrap();
+        assert_eq!(value.get::<String>(1).unwrap(), "first");
+        assert_eq!(value.get::<String>(2).unwrap(), "} --");
+        assert_eq!(value.get::<f64>("number").unwrap(), 1.0);
+        assert_eq!(value.get::<mlua::Table>("empty").unwrap().raw_len(), 0);
+
+        let inner: mlua::Table = value.get("inner").unwrap();
+        assert_eq!(inner.get::<String>("end").unwrap(), "keyword key");
+        assert_eq!(inner.get::<i64>("with space").unwrap(), 1);
+        assert!(inner.get::<bool>("flag").unwrap());
+    }
+
+    #[test]
+    fn chunks_keep_comments() {
+        let mut chunk = LuaChunk::new();
+        chunk.comment("Generated\nby goat").assign("hostname", "goat\"OS").blank().assign("packages", vec!["base"]);
+
+        assert_eq!(chunk.render(), "-- Generated\n-- by goat\nhostname = \"goat\\\"OS\"\n\npackages = {\n    \"base\",\n}\n");
+        assert_eq!(evaluate::<String>(chunk.render(), "hostname"), "goat\"OS");
+    }
+
+    #[test]
+    fn edits_keep_comments_and_unrelated_code() {
+        let source = "\
+-- My machine } {
+hostname = \"goat\" -- not \"goatOS\"
+
+--[[ packages = { \"fake\" } ]]
+packages = {
+    \"base\", -- the base system
+    -- \"commented-out\",
+    \"vim\",
+    [[long } string]],
+}
+
+local note = \"packages = {}\"
+";
+        let mut lua_source = LuaSource::parse(source.to_owned()).unwrap();
+
+        assert!(lua_source.list_insert("packages", "git").unwrap());
+        assert!(!lua_source.list_insert("packages", "vim").unwrap());
+        assert!(lua_source.list_remove("packages", "base").unwrap());
+        assert!(!lua_source.list_remove("packages", "commented-out").unwrap());
+
+        assert_eq!(lua_source.as_str(), "\
+-- My machine } {
+hostname = \"goat\" -- not \"goatOS\"
+
+--[[ packages = { \"fake\" } ]]
+packages = {
+    -- \"commented-out\",
+    \"vim\",
+    [[long } string]],
+    \"git\",
+}
+
+local note = \"packages = {}\"
+");
+        let packages: Vec<String> = evaluate(lua_source.as_str(), "packages");
+        assert_eq!(packages, ["vim", "long } string", "git"]);
+    }
+
+    #[test]
+    fn inserts_keep_sort_order_and_style() {
+        let mut multiline = LuaSource::parse(String::from("packages = {\n    \"a\",\n    \"c\"\n}\n")).unwrap();
+        multiline.list_insert("packages", "b").unwrap();
+        multiline.list_insert("packages", "d").unwrap();
+        assert_eq!(multiline.as_str(), "packages = {\n    \"a\",\n    \"b\",\n    \"c\",\n    \"d\"\n}\n");
+
+        let mut inline = LuaSource::parse(String::from("packages = { \"a\", \"c\" } -- inline\n")).unwrap();
+        inline.list_insert("packages", "b").unwrap();
+        inline.list_remove("packages", "c").unwrap();
+        assert_eq!(inline.as_str(), "packages = { \"a\", \"b\" } -- inline\n");
+
+        let mut missing = LuaSource::parse(String::from("hostname = \"goat\"")).unwrap();
+        missing.list_insert("packages", "it's").unwrap();
+        assert_eq!(missing.as_str(), "hostname = \"goat\"\npackages = {\n    \"it's\",\n}\n");
+    }
+
+    #[test]
+    fn set_global_replaces_in_place() {
+        let mut source = LuaSource::parse(String::from("-- name\nhostname = \"old\" -- keep me\nx = 1\n")).unwrap();
+        source.set_global("hostname", &LuaValue::from("new")).unwrap();
+        assert_eq!(source.as_str(), "-- name\nhostname = \"new\" -- keep me\nx = 1\n");
+        assert!(source.has_global("x"));
+        assert!(!source.has_global("name"));
+    }
+
+    #[test]
+    fn complex_values_are_left_alone() {
+        let mut source = LuaSource::parse(String::from("packages = base_packages .. { \"vim\" }\n")).unwrap();
+        assert!(source.list_insert("packages", "git").is_err());
+        assert!(source.has_global("packages"));
+
+        assert!(LuaSource::parse(String::from("x = \"unterminated")).is_err());
+    }
+
+    #[test]
+    fn list_values_reads_plain_strings() {
+        let source = LuaSource::parse(String::from("imports = {\n    \"a.lua\", -- first\n    'b.lua';\n    helper(),\n}\n")).unwrap();
+        assert_eq!(source.list_values("imports").unwrap(), ["a.lua", "b.lua"]);
+        assert!(source.list_values("packages").unwrap().is_empty());
+    }
+
+    #[test]
+    fn pinned_packages_are_matched_by_name() {
+        let mut source = LuaSource::parse(String::from(
+            "packages = {\n    \"base\",\n    { name = \"linux-zen\", version = \"6.9.1\", hold = true }, -- pinned\n    \"vim\",\n    { options = { name = \"other\" } },\n}\n"
+        )).unwrap();
+        assert_eq!(source.list_values("packages").unwrap(), ["base", "linux-zen", "vim"]);
+        assert!(!source.list_insert("packages", "linux-zen").unwrap());
+
+        assert!(source.list_remove("packages", "linux-zen").unwrap());
+        assert!(!source.list_remove("packages", "other").unwrap());
+        assert_eq!(source.as_str(), "packages = {\n    \"base\",\n    \"vim\",\n    { options = { name = \"other\" } },\n}\n");
+
+        // The last entry, without a trailing comma.
+        let mut source = LuaSource::parse(String::from("packages = { \"base\", { name = \"git\", version = \"2.0\" } }\n")).unwrap();
+        assert!(source.list_insert("packages", "vim").unwrap());
+        assert_eq!(source.as_str(), "packages = { \"base\", { name = \"git\", version = \"2.0\" }, \"vim\" }\n");
+        assert!(source.list_remove("packages", "vim").unwrap());
+        assert!(source.list_remove("packages", "git").unwrap());
+        assert_eq!(source.as_str(), "packages = { \"base\" }\n");
+    }
+}
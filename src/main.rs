@@ -6,9 +6,30 @@ mod service_manager;
 mod from_file;
 mod sync;
 mod stage;
+mod status;
+mod watch;
+mod import;
+mod command;
+mod lua_writer;
+mod merge;
+mod edit;
+mod check;
+mod fetch;
+mod eval;
+mod plan;
+mod repl;
+mod lua_types;
+mod events;
+mod history;
+mod git;
+mod hooks;
+mod order;
+mod stage_context;
 
+use std::path::PathBuf;
 use std::process::exit;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use events::OutputFormat;
 use goat::Goat;
 
 #[derive(Parser, Debug)]
@@ -22,31 +43,350 @@ struct Args {
     
     /// Delete all cache files before processing anything else
     #[arg(short='C', long)]
-    recache: bool
+    recache: bool,
+    
+    /// Print human readable logs or one JSON event per line for other programs, given before the
+    /// subcommand (`goat --output json sync`)
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+    
+    #[command(subcommand)]
+    command: Option<Command>
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show how far the system has drifted from the configuration without syncing.
+    ///
+    /// Exits with 0 when in sync, 1 when drifted and 2 on error.
+    Status {
+        /// Print the drift report as JSON
+        #[arg(long)]
+        json: bool
+    },
+    
+    /// Check for drift periodically and whenever the configuration directory changes.
+    #[command(visible_alias = "daemon")]
+    Watch {
+        /// Seconds between drift checks
+        #[arg(short, long, default_value_t = 900, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+        
+        /// Stage to sync automatically when it drifts, can be given multiple times
+        #[arg(long = "sync-stage", value_name = "STAGE")]
+        sync_stages: Vec<String>,
+        
+        /// Run a single check and exit with the same codes as `status`
+        #[arg(long)]
+        once: bool
+    },
+    
+    /// Generate a systemd service and timer that run `goat watch --once` periodically.
+    WatchUnits {
+        /// Seconds between drift checks
+        #[arg(short, long, default_value_t = 900, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+        
+        /// Stage to sync automatically when it drifts, can be given multiple times
+        #[arg(long = "sync-stage", value_name = "STAGE")]
+        sync_stages: Vec<String>,
+        
+        /// Write the units into this directory (ex: /etc/systemd/system) instead of printing them
+        #[arg(short, long)]
+        output: Option<PathBuf>
+    },
+    
+    /// Capture the running system into a configuration.
+    Import {
+        /// Directory to write config.lua and its modules into, defaults to the configuration directory
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        
+        /// Modified /etc file or directory to include, can be given multiple times. Files that might
+        /// hold secrets (not world readable, ssh host keys, ...) have to be given by their own path
+        #[arg(long = "etc", value_name = "PATH")]
+        etc_files: Vec<PathBuf>,
+        
+        /// Include every modified /etc file except the ones that might hold secrets
+        #[arg(long)]
+        all_etc: bool,
+        
+        /// List the modified /etc files that can be included and exit
+        #[arg(long)]
+        list_etc: bool,
+        
+        /// Overwrite an existing config.lua, its modules and copied files
+        #[arg(short, long)]
+        force: bool
+    },
+    
+    /// Sync the system configuration, same as `-s`.
+    Sync {
+        /// Install packages only from the package cache filled by `goat fetch`
+        #[arg(long)]
+        offline: bool,
+        
+        /// Role to enable on top of the configured `profiles`, can be given multiple times
+        #[arg(long = "profile", value_name = "ROLE")]
+        profiles: Vec<String>,
+        
+        /// Sync the configuration committed to this git repository (path or url) instead of the
+        /// configuration directory
+        #[arg(long, value_name = "REPOSITORY")]
+        from_git: Option<String>,
+        
+        /// Revision of the `--from-git` repository to sync
+        #[arg(long = "ref", value_name = "REV", requires = "from_git", default_value = "HEAD")]
+        rev: String
+    },
+    
+    /// Show what each enabled profile contributes and what a sync would change.
+    Plan {
+        /// Role to enable on top of the configured `profiles`, can be given multiple times
+        #[arg(long = "profile", value_name = "ROLE")]
+        profiles: Vec<String>
+    },
+    
+    /// Download every configured package and its dependencies for `goat sync --offline`.
+    Fetch,
+    
+    /// Print the configuration after every module, overlay and profile is applied.
+    ///
+    /// Doesn't touch the system or require root.
+    Eval {
+        /// Evaluate for this host instead of the running machine, applying `hosts/<HOST>.lua`
+        #[arg(long)]
+        host: Option<String>,
+        
+        /// Role to enable on top of the configured `profiles`, can be given multiple times
+        #[arg(long = "profile", value_name = "ROLE")]
+        profiles: Vec<String>,
+        
+        /// Print the evaluated configuration as lua (default)
+        #[arg(long, group = "format")]
+        lua: bool,
+        
+        /// Print the evaluated configuration along with the selected package manager and service
+        /// manager as JSON
+        #[arg(long, group = "format")]
+        json: bool,
+        
+        /// List which file (config.lua, a module, a profile or the host overlay) set each item
+        #[arg(long, group = "format")]
+        sources: bool
+    },
+    
+    /// Show the recorded syncs: when, who, which configuration and what changed.
+    History {
+        /// Only show the last N syncs
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+        
+        /// Print one JSON object per sync
+        #[arg(long)]
+        json: bool
+    },
+    
+    /// Start an interactive lua session with the `goat` module and the evaluated configuration
+    /// available as `config`.
+    Repl,
+    
+    /// Write lua language server definitions of the `goat` module, config.lua and the package and
+    /// service manager configurations.
+    LspTypes {
+        /// Directory to write the definitions into, defaults to /var/goat/types
+        #[arg(short, long)]
+        output: Option<PathBuf>
+    },
+    
+    /// Validate the configuration without touching the system.
+    ///
+    /// Every configured package is checked against the package manager's repositories. Exits with
+    /// 1 if any problem was found.
+    Check,
+    
+    /// Add packages to the configuration.
+    Add {
+        /// Packages to add, each one has to exist in the package manager's repositories
+        #[arg(required = true)]
+        packages: Vec<String>,
+        
+        /// Run the "Packages" stage right away
+        #[arg(short, long)]
+        sync: bool
+    },
+    
+    /// Remove packages from the configuration.
+    Remove {
+        /// Packages to remove
+        #[arg(required = true)]
+        packages: Vec<String>,
+        
+        /// Run the "Packages" stage right away
+        #[arg(short, long)]
+        sync: bool
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     
-    // Show all info level and above log messages in stdout.
-    env_logger::Builder::from_env(env_logger::Env::default()
-        .default_filter_or("info"))
-        .format_timestamp(None)
-        .init();
+    // Show all info level and above log messages.
+    events::init_logger(args.output);
+    
+    // Commands that have to work without a (valid) configuration file.
+    match &args.command {
+        Some(Command::WatchUnits { interval, sync_stages, output }) => {
+            match output {
+                Some(directory) => Goat::install_systemd_units(directory, *interval, sync_stages)?,
+                None => {
+                    for (file_name, contents) in Goat::systemd_units(*interval, sync_stages)? {
+                        println!("# {}\n{}", file_name, contents);
+                    }
+                }
+            }
+            
+            return Ok(())
+        }
+        // Loads the configuration itself for every check.
+        Some(Command::Watch { interval, sync_stages, once }) => {
+            let code = Goat::watch(&watch::WatchOptions {
+                interval: *interval,
+                sync_stages: sync_stages.clone(),
+                once: *once
+            }).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                status::EXIT_ERROR
+            });
+            exit(code);
+        }
+        Some(Command::Import { output, etc_files, all_etc, list_etc, force }) => {
+            let system = Goat::load_system(args.recache)?;
+            
+            if *list_etc {
+                for file in import::modified_etc_files(&system.package_manager)? {
+                    println!("{}", file.display());
+                }
+                return Ok(())
+            }
+            
+            system.import(&import::ImportOptions {
+                output: output.clone().unwrap_or_else(|| system.directories["configuration_directory"].clone()),
+                etc_files: etc_files.clone(),
+                all_etc_files: *all_etc,
+                force: *force
+            })?;
+            
+            return Ok(())
+        }
+        Some(Command::Eval { host, profiles, lua: _, json, sources }) => {
+            let system = Goat::load_read_only()?;
+            let host = host.clone().or_else(config::current_hostname);
+            let format = match (json, sources) {
+                (true, _) => eval::EvalFormat::Json,
+                (_, true) => eval::EvalFormat::Sources,
+                _ => eval::EvalFormat::Lua
+            };
+            
+            print!("{}", system.eval(host.as_deref(), profiles, format)?);
+            
+            return Ok(())
+        }
+        Some(Command::LspTypes { output }) => {
+            let system = Goat::load_system(args.recache)?;
+            
+            system.write_lua_types(output.as_ref().unwrap_or(&system.directories["types_directory"]))?;
+            
+            return Ok(())
+        }
+        Some(Command::History { limit, json }) => {
+            Goat::load_read_only()?.history(*limit, *json)?;
+            
+            return Ok(())
+        }
+        Some(Command::Repl) => {
+            Goat::load_read_only()?.repl()?;
+            
+            return Ok(())
+        }
+        Some(Command::Add { packages, sync }) | Some(Command::Remove { packages, sync }) => {
+            let system = Goat::load_system(args.recache)?;
+            
+            match &args.command {
+                Some(Command::Add { .. }) => system.add_packages(packages)?,
+                _ => system.remove_packages(packages)?
+            }
+            
+            if *sync {
+                // Evaluate the edited configuration.
+                Goat::load(false, &[])?.sync_stages(&[String::from("Packages")])?;
+            }
+            
+            return Ok(())
+        }
+        _ => {}
+    }
 
-    let system = match Goat::load(args.recache) {
+    let profiles = match &args.command {
+        Some(Command::Sync { profiles, .. }) | Some(Command::Plan { profiles }) => profiles.clone(),
+        _ => vec![]
+    };
+    
+    let loaded = match &args.command {
+        Some(Command::Sync { from_git: Some(source), rev, .. }) => Goat::load_from_git(args.recache, source, rev, &profiles),
+        _ => Goat::load(args.recache, &profiles)
+    };
+    
+    let mut system = match loaded {
         Ok(system) => system,
         Err(e) => {
             log::error!("{}", e);
-            exit(1);
+            match args.command {
+                Some(Command::Status { .. }) => exit(status::EXIT_ERROR),
+                _ => exit(1)
+            }
         }
     };
     
-    if args.sync {
+    if args.sync || matches!(args.command, Some(Command::Sync { .. })) {
+        system.offline = matches!(args.command, Some(Command::Sync { offline: true, .. }));
+        system.output = args.output;
+        
+        if !system.config.profiles.is_empty() {
+            log::info!("Enabled profiles: {}", system.config.profiles.join(", "));
+        }
+        
+        if system.git_source.is_none() {
+            system.warn_if_dirty();
+        }
+        
         log::info!("Syncing system...");
         system.sync()?;
         log::info!("Sync complete.");
     }
     
+    match args.command {
+        Some(Command::Status { json }) => {
+            let code = match system.status() {
+                Ok(status) => match status.print(json) {
+                    Ok(()) => status.exit_code(),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        status::EXIT_ERROR
+                    }
+                },
+                Err(e) => {
+                    log::error!("{}", e);
+                    status::EXIT_ERROR
+                }
+            };
+            exit(code);
+        }
+        Some(Command::Check) if !system.check()? => exit(1),
+        Some(Command::Fetch) => system.fetch()?,
+        Some(Command::Plan { .. }) => system.plan()?,
+        _ => {}
+    }
+    
     Ok(())
 }
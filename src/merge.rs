@@ -0,0 +1,366 @@
+use std::collections::{BTreeMap, HashSet};
+use anyhow::anyhow;
+use mlua::{Lua, Table, Value};
+// merge.rs
+//
+// Merging of lua tables, used to layer configuration files (modules, host overlays) on top of
+// each other.
+//
+// - Scalars (and anything replacing a non-table) override the base value.
+// - The sequence part of a table is appended to the base sequence, skipping entries the base
+//   already has. Entries are compared by value, or by their `name` field for tables such as
+//   `{ name = "linux-zen", version = "6.9.1" }`, which are merged into the existing entry.
+// - A `remove` list inside a top level table like `packages` removes those entries from the base
+//   sequence first. Deeper tables keep a `remove` key like any other.
+// - Every other key is merged recursively.
+
+/// The key of the list of entries to remove from a base sequence.
+const REMOVE_KEY: &str = "remove";
+
+/// The top level table of roles, see `Config::merge_profiles`. Roles are merged into the
+/// configuration only once they are enabled, so they are collected by name without being merged
+/// themselves and their `remove` lists are kept until then.
+const ROLES_KEY: &str = "roles";
+
+/// Which file contributed each merged item, keyed by the item's path like `packages.steam` or
+/// `hostname`.
+pub type Sources = BTreeMap<String, String>;
+
+/// What a sequence entry is compared by, `None` if it can't be compared.
+fn identity(value: &Value) -> Option<String> {
+    match value {
+        Value::String(string) => Some(string.to_string_lossy()),
+        Value::Integer(integer) => Some(integer.to_string()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Boolean(boolean) => Some(boolean.to_string()),
+        Value::Table(table) => table.get::<Option<String>>("name").ok().flatten(),
+        _ => None
+    }
+}
+
+/// Whether the table at `prefix` takes a `remove` list, only the tables directly inside a
+/// configuration file or role do. A `remove` option of a file or hook is a value like any other.
+fn takes_removals(prefix: &str) -> bool {
+    !prefix.is_empty() && !prefix.contains('.')
+}
+
+/// The path of `key` inside the table at `prefix`.
+fn item_path(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() { key.to_owned() } else { format!("{}.{}", prefix, key) }
+}
+
+/// Merges tables while keeping track of where every item came from.
+pub struct Merge<'a> {
+    lua: &'a Lua,
+    pub sources: Sources
+}
+
+impl<'a> Merge<'a> {
+    pub fn new(lua: &'a Lua) -> Self {
+        Merge {
+            lua,
+            sources: Sources::new()
+        }
+    }
+
+    /// Merge `overlay`, which was read from `source`, into `base` in place.
+    ///
+    /// With `strict` a scalar that a different source already set to another value is a conflict
+    /// and returns an error instead of being overridden. This is used for modules, which have no
+    /// order the user could rely on.
+    pub fn merge(&mut self, base: &Table, overlay: &Table, source: &str, strict: bool) -> anyhow::Result<()> {
+        self.merge_at(base, overlay, source, strict, "")
+    }
+
+    fn merge_at(&mut self, base: &Table, overlay: &Table, source: &str, strict: bool, prefix: &str) -> anyhow::Result<()> {
+        let mut entries: Vec<Value> = base.sequence_values::<Value>()
+            .collect::<Result<_, _>>()
+            .map_err(|e| anyhow!("{}", e))?;
+        let base_length = entries.len();
+
+        // Raw, module environments fall through to the globals of config.lua, which may have a
+        // `remove` of its own.
+        let removals = match takes_removals(prefix) {
+            true => overlay.raw_get::<Option<Table>>(REMOVE_KEY).map_err(|e| anyhow!("{}", e))?,
+            false => None
+        };
+
+        if let Some(remove) = removals {
+            let removed: HashSet<String> = remove.sequence_values::<Value>()
+                .filter_map(|value| value.ok().as_ref().and_then(identity))
+                .collect();
+
+            entries.retain(|entry| identity(entry).is_none_or(|entry_identity| !removed.contains(&entry_identity)));
+
+            for removed in &removed {
+                self.sources.remove(&item_path(prefix, removed));
+            }
+        }
+
+        for value in overlay.sequence_values::<Value>() {
+            let value = value.map_err(|e| anyhow!("{}", e))?;
+            let value_identity = identity(&value);
+            let existing = value_identity
+                .as_ref()
+                .and_then(|value_identity| entries.iter().position(|entry| identity(entry).as_ref() == Some(value_identity)));
+
+            match (existing, &value) {
+                (None, _) => entries.push(value),
+                // `{ name = "linux-zen", hold = true }` adds to the existing entry of the same name.
+                (Some(index), Value::Table(overlay_entry)) => match &entries[index] {
+                    Value::Table(base_entry) => {
+                        let path = item_path(prefix, value_identity.as_deref().unwrap_or_default());
+                        self.merge_at(base_entry, overlay_entry, source, strict, &path)?;
+                    },
+                    _ => entries[index] = value
+                },
+                (Some(_), _) => {}
+            }
+
+            // The first source to mention an entry keeps it.
+            if let Some(value_identity) = &value_identity {
+                self.sources.entry(item_path(prefix, value_identity)).or_insert_with(|| source.to_owned());
+            }
+        }
+
+        // Rewrite the whole sequence, removals leave holes otherwise.
+        for index in 1..=base_length.max(entries.len()) {
+            base.raw_set(index, entries.get(index - 1).cloned().unwrap_or(Value::Nil)).map_err(|e| anyhow!("{}", e))?;
+        }
+
+        let overlay_length = overlay.raw_len();
+
+        for pair in overlay.pairs::<Value, Value>() {
+            let (key, value) = pair.map_err(|e| anyhow!("{}", e))?;
+
+            // The sequence part and the removals were handled above.
+            let key_name = match &key {
+                Value::Integer(index) if *index >= 1 && (*index as usize) <= overlay_length => continue,
+                Value::String(string) if takes_removals(prefix) && string.to_string_lossy() == REMOVE_KEY => continue,
+                Value::String(string) => string.to_string_lossy(),
+                key => identity(key).unwrap_or_default()
+            };
+            let path = item_path(prefix, &key_name);
+
+            if prefix.is_empty() && key_name == ROLES_KEY && let Value::Table(roles) = &value {
+                self.merge_roles(base, roles, source, strict)?;
+                continue;
+            }
+
+            match (base.raw_get::<Value>(key.clone()).map_err(|e| anyhow!("{}", e))?, value) {
+                (Value::Table(base_value), Value::Table(overlay_value)) => {
+                    self.merge_at(&base_value, &overlay_value, source, strict, &path)?
+                },
+                (base_value, value) => {
+                    if strict && !base_value.is_nil() && identity(&base_value) != identity(&value)
+                        && let Some(previous_source) = self.sources.get(&path)
+                        && previous_source != source {
+                        return Err(anyhow!(
+                            "Conflicting values for \"{}\": {} sets {} but {} sets {}",
+                            path,
+                            previous_source,
+                            identity(&base_value).unwrap_or_else(|| base_value.type_name().to_owned()),
+                            source,
+                            identity(&value).unwrap_or_else(|| value.type_name().to_owned())
+                        ));
+                    }
+
+                    // Copy new tables through a merge so their `remove` lists don't end up in the
+                    // result.
+                    let value = match value {
+                        Value::Table(overlay_value) => {
+                            let table = self.lua.create_table().map_err(|e| anyhow!("{}", e))?;
+                            self.merge_at(&table, &overlay_value, source, strict, &path)?;
+                            Value::Table(table)
+                        },
+                        value => {
+                            self.sources.insert(path, source.to_owned());
+                            value
+                        }
+                    };
+
+                    base.raw_set(key, value).map_err(|e| anyhow!("{}", e))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add every role in `roles` to the roles table of `base` as is. A role defined by two sources
+    /// is a conflict with `strict`, otherwise the later definition replaces the earlier one.
+    fn merge_roles(&mut self, base: &Table, roles: &Table, source: &str, strict: bool) -> anyhow::Result<()> {
+        let base_roles = match base.raw_get::<Value>(ROLES_KEY).map_err(|e| anyhow!("{}", e))? {
+            Value::Table(base_roles) => base_roles,
+            _ => {
+                let base_roles = self.lua.create_table().map_err(|e| anyhow!("{}", e))?;
+                base.raw_set(ROLES_KEY, &base_roles).map_err(|e| anyhow!("{}", e))?;
+                base_roles
+            }
+        };
+
+        for pair in roles.pairs::<Value, Value>() {
+            let (name, role) = pair.map_err(|e| anyhow!("{}", e))?;
+            let path = item_path(ROLES_KEY, &identity(&name).unwrap_or_default());
+
+            if strict && let Some(previous_source) = self.sources.get(&path) && previous_source != source {
+                return Err(anyhow!("Conflicting values for \"{}\": {} and {} both define it", path, previous_source, source));
+            }
+
+            base_roles.raw_set(name, role).map_err(|e| anyhow!("{}", e))?;
+            self.sources.insert(path, source.to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluate `source` as a chunk returning a table.
+    fn table(lua: &Lua, source: &str) -> Table {
+        lua.load(source).eval().expect("test table doesn't evaluate")
+    }
+
+    /// The sequence part of `table` as strings.
+    fn strings(table: &Table) -> Vec<String> {
+        table.sequence_values::<String>().collect::<Result<_, _>>().expect("sequence isn't strings")
+    }
+
+    #[test]
+    fn scalars_override_and_tables_merge() {
+        let lua = Lua::new();
+        let base = table(&lua, r#"return { hostname = "a", services = { sshd = true } }"#);
+        let overlay = table(&lua, r#"return { hostname = "b", services = { cups = true } }"#);
+
+        let mut merge = Merge::new(&lua);
+        merge.merge(&base, &overlay, "overlay", false).unwrap();
+
+        assert_eq!(base.get::<String>("hostname").unwrap(), "b");
+        let services: Table = base.get("services").unwrap();
+        assert!(services.get::<bool>("sshd").unwrap());
+        assert!(services.get::<bool>("cups").unwrap());
+        assert_eq!(merge.sources.get("hostname").map(String::as_str), Some("overlay"));
+    }
+
+    #[test]
+    fn sequences_append_without_duplicates() {
+        let lua = Lua::new();
+        let base = lua.create_table().unwrap();
+
+        let mut merge = Merge::new(&lua);
+        merge.merge(&base, &table(&lua, r#"return { packages = { "vim", "git" } }"#), "base", false).unwrap();
+        merge.merge(&base, &table(&lua, r#"return { packages = { "git", "steam" } }"#), "overlay", false).unwrap();
+
+        assert_eq!(strings(&base.get("packages").unwrap()), ["vim", "git", "steam"]);
+        assert_eq!(merge.sources.get("packages.steam").map(String::as_str), Some("overlay"));
+        assert_eq!(merge.sources.get("packages.git").map(String::as_str), Some("base"));
+    }
+
+    #[test]
+    fn named_entries_merge_into_each_other() {
+        let lua = Lua::new();
+        let base = table(&lua, r#"return { packages = { "vim", { name = "linux-zen", version = "6.9.1" } } }"#);
+        let overlay = table(&lua, r#"return { packages = { { name = "linux-zen", hold = true } } }"#);
+
+        Merge::new(&lua).merge(&base, &overlay, "overlay", false).unwrap();
+
+        let packages: Table = base.get("packages").unwrap();
+        assert_eq!(packages.raw_len(), 2);
+        let kernel: Table = packages.get(2).unwrap();
+        assert_eq!(kernel.get::<String>("version").unwrap(), "6.9.1");
+        assert!(kernel.get::<bool>("hold").unwrap());
+    }
+
+    #[test]
+    fn remove_lists_remove_entries_and_are_dropped() {
+        let lua = Lua::new();
+        let base = table(&lua, r#"return { packages = { "vim", "nano", { name = "linux-zen" }, "git" } }"#);
+        let overlay = table(&lua, r#"return { packages = { "emacs", remove = { "nano", "linux-zen" } }, new = { remove = { "x" }, "y" } }"#);
+
+        Merge::new(&lua).merge(&base, &overlay, "overlay", false).unwrap();
+
+        let packages: Table = base.get("packages").unwrap();
+        assert_eq!(strings(&packages), ["vim", "git", "emacs"]);
+        assert_eq!(packages.raw_len(), 3);
+        assert!(packages.get::<Value>(REMOVE_KEY).unwrap().is_nil());
+
+        let new: Table = base.get("new").unwrap();
+        assert_eq!(strings(&new), ["y"]);
+        assert!(new.get::<Value>(REMOVE_KEY).unwrap().is_nil());
+    }
+
+    #[test]
+    fn strict_merges_reject_conflicts_between_sources() {
+        let lua = Lua::new();
+        let base = lua.create_table().unwrap();
+
+        let mut merge = Merge::new(&lua);
+        merge.merge(&base, &table(&lua, r#"return { shell = "zsh", packages = { "vim" } }"#), "a.lua", true).unwrap();
+
+        // The same value or more entries are fine.
+        merge.merge(&base, &table(&lua, r#"return { shell = "zsh", packages = { "git" } }"#), "b.lua", true).unwrap();
+
+        let error = merge.merge(&base, &table(&lua, r#"return { shell = "fish" }"#), "c.lua", true).unwrap_err();
+        assert_eq!(error.to_string(), "Conflicting values for \"shell\": b.lua sets zsh but c.lua sets fish");
+
+        // Without strict the later source wins.
+        merge.merge(&base, &table(&lua, r#"return { shell = "fish" }"#), "c.lua", false).unwrap();
+        assert_eq!(base.get::<String>("shell").unwrap(), "fish");
+    }
+
+    #[test]
+    fn remove_is_not_inherited_through_index() {
+        let lua = Lua::new();
+        let base = table(&lua, r#"return { packages = { "vim" } }"#);
+
+        // Module environments fall through to the globals of config.lua like this.
+        let overlay = table(&lua, r#"return { packages = setmetatable({ "git" }, { __index = { remove = { "vim" } } }) }"#);
+
+        Merge::new(&lua).merge(&base, &overlay, "module.lua", true).unwrap();
+
+        assert_eq!(strings(&base.get("packages").unwrap()), ["vim", "git"]);
+    }
+
+    #[test]
+    fn remove_is_a_plain_key_below_the_top_level() {
+        let lua = Lua::new();
+        let base = table(&lua, r#"return { remove = "top", files = { ["/etc/a"] = { "x" } } }"#);
+        let overlay = table(&lua, r#"return { files = { ["/etc/a"] = { remove = { "x" } }, ["/etc/b"] = { remove = true } } }"#);
+
+        Merge::new(&lua).merge(&base, &overlay, "overlay", false).unwrap();
+
+        assert_eq!(base.get::<String>(REMOVE_KEY).unwrap(), "top");
+        let files: Table = base.get("files").unwrap();
+        let a: Table = files.get("/etc/a").unwrap();
+        assert_eq!(strings(&a), ["x"]);
+        assert_eq!(strings(&a.get(REMOVE_KEY).unwrap()), ["x"]);
+        let b: Table = files.get("/etc/b").unwrap();
+        assert!(b.get::<bool>(REMOVE_KEY).unwrap());
+    }
+
+    #[test]
+    fn roles_keep_their_remove_lists_until_merged() {
+        let lua = Lua::new();
+        let config = lua.create_table().unwrap();
+
+        let mut merge = Merge::new(&lua);
+        merge.merge(&config, &table(&lua, r#"return {
+            packages = { "vim", "nano" },
+            roles = { minimal = { packages = { "less", remove = { "nano" } } } }
+        }"#), "config.lua", false).unwrap();
+        merge.merge(&config, &table(&lua, r#"return { roles = { desktop = { packages = { "firefox" } } } }"#), "desktop.lua", true).unwrap();
+
+        let roles: Table = config.get("roles").unwrap();
+        assert!(roles.contains_key("desktop").unwrap());
+
+        let minimal: Table = roles.get("minimal").unwrap();
+        merge.merge(&config, &minimal, "profile:minimal", true).unwrap();
+        assert_eq!(strings(&config.get("packages").unwrap()), ["vim", "less"]);
+
+        let error = merge.merge(&config, &table(&lua, r#"return { roles = { desktop = {} } }"#), "other.lua", true).unwrap_err();
+        assert_eq!(error.to_string(), "Conflicting values for \"roles.desktop\": desktop.lua and other.lua both define it");
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::{HashMap, HashSet};
+use anyhow::anyhow;
+use crate::stage::Stage;
+// order.rs
+//
+// All logic related to ordering stages by their dependencies (`Stage::after` and `Stage::before`)
+// should be placed here.
+
+/// For every stage, the stages that have to be applied before it.
+fn dependencies(stages: &[Box<dyn Stage>]) -> anyhow::Result<HashMap<String, HashSet<String>>> {
+    let names: HashSet<String> = stages.iter().map(|stage| stage.name()).collect();
+    let mut dependencies: HashMap<String, HashSet<String>> = names.iter()
+        .map(|name| (name.clone(), HashSet::new()))
+        .collect();
+
+    let unknown = |stage: &str, dependency: &str| {
+        anyhow!("Stage \"{}\" is ordered against \"{}\" which is not a stage", stage, dependency)
+    };
+
+    for stage in stages {
+        let name = stage.name();
+
+        for dependency in stage.after() {
+            if !names.contains(&dependency) {
+                return Err(unknown(&name, &dependency));
+            }
+            dependencies.entry(name.clone()).or_default().insert(dependency);
+        }
+
+        // `before` is the same as the other stage being `after` this one.
+        for dependent in stage.before() {
+            if !names.contains(&dependent) {
+                return Err(unknown(&name, &dependent));
+            }
+            dependencies.entry(dependent).or_default().insert(name.clone());
+        }
+    }
+
+    Ok(dependencies)
+}
+
+/// Follow unapplied dependencies from one of the `remaining` stages until a stage repeats, the
+/// stages in between form a cycle.
+fn find_cycle(remaining: &[String], dependencies: &HashMap<String, HashSet<String>>) -> Vec<String> {
+    let mut path: Vec<String> = vec![];
+    let mut current = remaining.first().cloned();
+
+    while let Some(name) = current {
+        if let Some(start) = path.iter().position(|seen| *seen == name) {
+            let mut cycle = path.split_off(start);
+            cycle.push(name);
+            return cycle;
+        }
+
+        // Sorted so the same cycle is reported every time.
+        let mut next: Vec<&String> = dependencies[&name].iter()
+            .filter(|dependency| remaining.contains(dependency))
+            .collect();
+        next.sort();
+
+        current = next.first().map(|next| (*next).clone());
+        path.push(name);
+    }
+
+    path
+}
+
+/// Sort `stages` so every stage comes after its dependencies. Stages that don't depend on each
+/// other keep the order they were given in.
+///
+/// Dependencies on stages that don't exist and cycles are errors.
+pub fn order_stages(stages: Vec<Box<dyn Stage>>) -> anyhow::Result<Vec<Box<dyn Stage>>> {
+    let dependencies = dependencies(&stages)?;
+
+    let mut remaining: Vec<Box<dyn Stage>> = stages;
+    let mut ordered: Vec<Box<dyn Stage>> = Vec::with_capacity(remaining.len());
+    let mut applied: HashSet<String> = HashSet::new();
+
+    while !remaining.is_empty() {
+        // The first stage that has every dependency applied.
+        let ready = remaining.iter().position(|stage| {
+            dependencies[&stage.name()].iter().all(|dependency| applied.contains(dependency))
+        });
+
+        match ready {
+            Some(index) => {
+                let stage = remaining.remove(index);
+                applied.insert(stage.name());
+                ordered.push(stage);
+            },
+            None => {
+                let names: Vec<String> = remaining.iter().map(|stage| stage.name()).collect();
+                let cycle = find_cycle(&names, &dependencies);
+
+                return Err(anyhow!("Stages depend on each other in a cycle: {}",
+                    cycle.iter().map(|name| format!("\"{}\"", name)).collect::<Vec<_>>().join(" after ")));
+            }
+        }
+    }
+
+    Ok(ordered)
+}
+
+/// Split ordered stages into groups that are applied one after another. Consecutive stages that
+/// are `parallel_safe` and don't depend on each other share a group and may be applied at the
+/// same time, every other stage is a group of its own.
+pub fn parallel_groups(stages: &[Box<dyn Stage>]) -> Vec<&[Box<dyn Stage>]> {
+    let mut groups = vec![];
+    let mut start = 0;
+
+    for index in 1..=stages.len() {
+        let joins_group = index < stages.len() && {
+            let stage = &stages[index];
+            let group = &stages[start..index];
+
+            stage.parallel_safe()
+                && group.iter().all(|member| member.parallel_safe())
+                && group.iter().all(|member| {
+                    !stage.after().contains(&member.name()) && !member.before().contains(&stage.name())
+                })
+        };
+
+        if !joins_group {
+            groups.push(&stages[start..index]);
+            start = index;
+        }
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::goat::Goat;
+    use crate::stage::StageResult;
+
+    struct TestStage {
+        name: &'static str,
+        after: Vec<&'static str>,
+        before: Vec<&'static str>,
+        parallel: bool
+    }
+
+    impl Stage for TestStage {
+        fn name(&self) -> String { self.name.to_owned() }
+
+        fn apply(&self, _goat: &Goat) -> anyhow::Result<StageResult> { Ok(StageResult::Done) }
+
+        fn after(&self) -> Vec<String> { self.after.iter().map(|name| name.to_string()).collect() }
+
+        fn before(&self) -> Vec<String> { self.before.iter().map(|name| name.to_string()).collect() }
+
+        fn parallel_safe(&self) -> bool { self.parallel }
+    }
+
+    /// A stage named `name` applied after the stages in `after`.
+    fn stage(name: &'static str, after: &[&'static str]) -> Box<dyn Stage> {
+        Box::new(TestStage { name, after: after.to_vec(), before: vec![], parallel: false })
+    }
+
+    fn parallel_stage(name: &'static str, after: &[&'static str]) -> Box<dyn Stage> {
+        Box::new(TestStage { name, after: after.to_vec(), before: vec![], parallel: true })
+    }
+
+    fn names(stages: &[Box<dyn Stage>]) -> Vec<String> {
+        stages.iter().map(|stage| stage.name()).collect()
+    }
+
+    #[test]
+    fn independent_stages_keep_their_order() {
+        let ordered = order_stages(vec![stage("a", &[]), stage("b", &[]), stage("c", &[])]).unwrap();
+        assert_eq!(names(&ordered), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn stages_come_after_their_dependencies() {
+        let ordered = order_stages(vec![
+            stage("a", &["c"]),
+            stage("b", &[]),
+            Box::new(TestStage { name: "c", after: vec![], before: vec!["b"], parallel: false }),
+            stage("d", &["a"])
+        ]).unwrap();
+
+        assert_eq!(names(&ordered), ["c", "a", "b", "d"]);
+    }
+
+    #[test]
+    fn unknown_dependencies_are_errors() {
+        let error = order_stages(vec![stage("a", &["Pakages"])]).err().unwrap();
+        assert_eq!(error.to_string(), "Stage \"a\" is ordered against \"Pakages\" which is not a stage");
+    }
+
+    #[test]
+    fn cycles_are_reported() {
+        let error = order_stages(vec![stage("x", &[]), stage("a", &["c"]), stage("b", &["a"]), stage("c", &["b"])]).err().unwrap();
+        assert_eq!(error.to_string(), "Stages depend on each other in a cycle: \"a\" after \"c\" after \"b\" after \"a\"");
+    }
+
+    #[test]
+    fn find_cycle_skips_stages_leading_into_it() {
+        let dependencies: HashMap<String, HashSet<String>> = [
+            ("entry", vec!["a"]),
+            ("a", vec!["b"]),
+            ("b", vec!["a"])
+        ].into_iter()
+            .map(|(name, after)| (name.to_owned(), after.into_iter().map(str::to_owned).collect()))
+            .collect();
+        let remaining: Vec<String> = ["entry", "a", "b"].into_iter().map(str::to_owned).collect();
+
+        assert_eq!(find_cycle(&remaining, &dependencies), ["a", "b", "a"]);
+    }
+
+    #[test]
+    fn parallel_groups_split_at_dependencies_and_unsafe_stages() {
+        let stages = vec![
+            parallel_stage("a", &[]),
+            parallel_stage("b", &[]),
+            parallel_stage("c", &["a"]),
+            stage("d", &[]),
+            parallel_stage("e", &[]),
+            parallel_stage("f", &[])
+        ];
+
+        let groups: Vec<Vec<String>> = parallel_groups(&stages).into_iter().map(names).collect();
+        assert_eq!(groups, [vec!["a", "b"], vec!["c"], vec!["d"], vec!["e", "f"]]);
+        assert!(parallel_groups(&[]).is_empty());
+    }
+}
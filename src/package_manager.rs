@@ -1,10 +1,15 @@
 use goat_lua::GoatLua;
-use std::collections::{HashSet};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
 use anyhow::anyhow;
 use std::process::Command;
-use goat_lua_macro::FromLuaFile;
+use goat_lua_macro::{FromLuaFile, LuaTypes};
+use serde::Serialize;
+use crate::command;
+use crate::config::Repository;
 
-#[derive(FromLuaFile)]
+#[derive(FromLuaFile, Serialize, LuaTypes)]
 pub struct PackageManager {
     /// The name of any applicable package manager binary.
     ///
@@ -18,6 +23,40 @@ pub struct PackageManager {
     /// `pacman -S {}`
     install_command: String,
     
+    /// Command to install packages that have to be built by an unprivileged user, like AUR
+    /// packages. It runs as the configured `build_user` and is used for every package not listed
+    /// by `list_repository_packages_command`. Optional.
+    /// 
+    /// ex: `paru -S --noconfirm {}`
+    unprivileged_install_command: Option<String>,
+    
+    /// Command to list every package in the repositories `install_command` can install as root.
+    /// 
+    /// ex: `pacman -Slq`
+    list_repository_packages_command: Option<String>,
+    
+    /// Command to download packages and all of their dependencies into `{cache}` without
+    /// installing them. Optional.
+    /// 
+    /// ex: `pacman -Syw --noconfirm --cachedir {cache} --dbpath {cache}/db {}`
+    download_command: Option<String>,
+    
+    /// Command to download version `{version}` of the package `{name}` into `{cache}`, used by
+    /// `goat fetch` for pinned packages. Optional, pinned versions can't be fetched without it.
+    /// 
+    /// ex: `cd {cache} && apt-get download {name}={version}`
+    download_version_command: Option<String>,
+    
+    /// Command to install packages using nothing but the packages downloaded into `{cache}`.
+    /// Optional.
+    /// 
+    /// Without `{}` the command installs everything in the cache, it is run once instead of per
+    /// batch and whatever ended up installed counts as installed.
+    /// 
+    /// ex: `apt-get install -y --no-download -o Dir::Cache::archives={cache} {}`
+    /// or: `pacman -U --noconfirm --needed --asdeps {cache}/*.pkg.tar.zst`
+    offline_install_command: Option<String>,
+    
     /// Uninstall a package.
     remove_command: String,
     
@@ -38,43 +77,436 @@ pub struct PackageManager {
     /// explicitly installed.
     list_all_packages_command: String,
     
+    /// Command to get a list of configuration files (full paths) owned by a package that differ
+    /// from the packaged version. Optional, `goat import` can't pick `/etc` files without it.
+    /// 
+    /// ex: `pacman -Qii | awk '/^MODIFIED/ {print $2}'`
+    list_modified_files_command: Option<String>,
+    
+    /// Command to get a list of every file (full paths) owned by an installed package. Anything in
+    /// `/etc` not in this list was created by the user or a program. Optional, `goat import` can't
+    /// pick `/etc` files without it.
+    /// 
+    /// ex: `pacman -Qlq`
+    list_owned_files_command: Option<String>,
+    
+    /// Command that succeeds only if the package exists in the package manager's repositories.
+    /// Optional, without it every configured package is assumed to exist.
+    /// 
+    /// ex: `pacman -Si {}`
+    info_command: Option<String>,
+    
+    /// Command to list the names of every package in the repositories matching a regular
+    /// expression. Used to suggest alternatives for unknown packages. It has to exit successfully
+    /// when nothing matches. Optional, unknown packages get no suggestions without it.
+    /// 
+    /// ex: `pacman -Ssq {} || true`
+    search_command: Option<String>,
+    
+    /// Command to list the members of a package group, it should output nothing and exit
+    /// successfully if the name isn't a group. Optional, without it every name is a package.
+    /// 
+    /// ex: `pacman -Sgq {} || true`
+    list_group_members_command: Option<String>,
+    
+    /// Command to mark installed packages as installed as a dependency, so the package manager
+    /// can remove them once nothing depends on them anymore. Optional.
+    /// 
+    /// ex: `pacman -D --asdeps {}`
+    /// or: `apt-mark auto {}`
+    mark_as_dependency_command: Option<String>,
+    
+    /// Command to mark installed packages as explicitly installed. Optional.
+    /// 
+    /// ex: `pacman -D --asexplicit {}`
+    /// or: `apt-mark manual {}`
+    mark_as_explicit_command: Option<String>,
+    
+    /// Command to list installed dependency packages nothing depends on anymore, exiting
+    /// successfully when there are none. Optional.
+    /// 
+    /// ex: `pacman -Qdtq || true`
+    list_orphans_command: Option<String>,
+    
+    /// Command to install a specific version of a package, using `{name}` and `{version}`.
+    /// Optional.
+    /// 
+    /// ex: `apt-get install -y --allow-downgrades {name}={version}`
+    install_version_command: Option<String>,
+    
+    /// Command to keep a package (`{name}`) from being upgraded, it has to be safe to run more
+    /// than once. Optional.
+    /// 
+    /// ex: `apt-mark hold {name}`
+    hold_command: Option<String>,
+    
+    /// Command to let a package (`{name}`) held by `hold_command` be upgraded again, it has to be
+    /// safe to run when the package isn't held. Optional.
+    /// 
+    /// ex: `apt-mark unhold {name}`
+    unhold_command: Option<String>,
+    
+    /// Command to list every installed package followed by its version, one per line. Optional,
+    /// only needed to pin package versions.
+    /// 
+    /// ex: `pacman -Q`
+    list_versions_command: Option<String>,
+    
+    /// File every configured repository is rendered into, owned by goat. Optional.
+    /// 
+    /// ex: `/etc/pacman.d/goat-repositories.conf`
+    /// or: `/etc/apt/sources.list.d/goat.list`
+    repositories_file: Option<String>,
+    
+    /// What a single repository looks like in `repositories_file`. `{name}`, `{key}` and
+    /// `{priority}` are replaced with the repository's values, lines containing `{url}` are
+    /// repeated for every mirror.
+    /// 
+    /// ex: `[{name}]\nServer = {url}\n`
+    repository_template: Option<String>,
+    
+    /// Command to trust a repository's signing key (`{key}`, `{name}`). Optional.
+    /// 
+    /// ex: `pacman-key --recv-keys {key} && pacman-key --lsign-key {key}`
+    import_key_command: Option<String>,
+    
+    /// Command run after `repositories_file` changed so the new repositories can be used. Optional.
+    /// 
+    /// This runs in the middle of a sync, before packages are installed, so it must not upgrade
+    /// anything. Package managers that can't refresh without upgrading (pacman) leave the refresh
+    /// to `full_system_update_command`, which only runs with `full_upgrade_on_repository_change`.
+    /// 
+    /// ex: `apt-get update`
+    refresh_repositories_command: Option<String>,
+    
     /// A list of packages REQUIRED to be installed by the package manager.
     /// 
     /// ex: pacman has "core" and "linux"/"linux-zen"
     core_packages: Vec<String>
 }
 
+/// How `PackageManager::install` splits packages into install commands.
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallStrategy {
+    /// Every package in a single command, one failing package fails all of them.
+    Single,
+    
+    /// Commands of at most this many packages. A failing batch fails every package in it but the
+    /// other batches are still installed.
+    Batches(usize),
+    
+    /// Like `Batches` but the packages of a failing batch are retried one at a time, so only the
+    /// broken packages fail.
+    Fallback(usize)
+}
+
+impl InstallStrategy {
+    /// Install `packages` with `install`, split up according to the strategy.
+    fn run(&self, packages: &[&str], install: impl Fn(&[&str]) -> anyhow::Result<()>) -> InstallReport {
+        // `chunks` can't take an empty batch, which `Single` asks for without packages.
+        let batch_size = match self {
+            InstallStrategy::Single => packages.len().max(1),
+            InstallStrategy::Batches(size) | InstallStrategy::Fallback(size) => *size
+        };
+        
+        let mut report = InstallReport::default();
+        
+        for batch in packages.chunks(batch_size) {
+            match install(batch) {
+                Ok(()) => report.installed.extend(batch.iter().map(|package| package.to_string())),
+                Err(e) if matches!(self, InstallStrategy::Fallback(_)) && batch.len() > 1 => {
+                    log::debug!("Batch failure: {}", e);
+                    log::warn!("Installing {} package(s) together failed, retrying them one at a time.", batch.len());
+                    
+                    for package in batch {
+                        match install(&[package]) {
+                            Ok(()) => report.installed.push(package.to_string()),
+                            Err(e) => report.failed.push((package.to_string(), e.to_string()))
+                        }
+                    }
+                },
+                Err(e) => report.failed.extend(batch.iter().map(|package| (package.to_string(), e.to_string())))
+            }
+        }
+        
+        report
+    }
+}
+
+/// The outcome of `PackageManager::install`.
+#[derive(Default)]
+pub struct InstallReport {
+    pub installed: Vec<String>,
+    
+    /// Packages that couldn't be installed along with the reason.
+    pub failed: Vec<(String, String)>
+}
+
+impl InstallReport {
+    fn extend(&mut self, other: InstallReport) {
+        self.installed.extend(other.installed);
+        self.failed.extend(other.failed);
+    }
+}
+
+/// Maximum amount of suggestions given for a single unknown package.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// A configured package that is neither installed nor available in the repositories.
+pub struct UnknownPackage {
+    pub name: String,
+    
+    /// Similarly named packages that do exist, closest first.
+    pub suggestions: Vec<String>
+}
+
+impl fmt::Display for UnknownPackage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self.name)?;
+        
+        if !self.suggestions.is_empty() {
+            let suggestions: Vec<String> = self.suggestions.iter().map(|s| format!("\"{}\"", s)).collect();
+            write!(f, " (did you mean {}?)", suggestions.join(", "))?;
+        }
+        
+        Ok(())
+    }
+}
+
 impl PackageManager {
-/// Get a Vec<String> of explicitly installed packages.
+    /// Get a Vec<String> of explicitly installed packages.
     pub fn explicit_packages(&self) -> anyhow::Result<Vec<String>> {
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(&self.list_explicit_packages_command)
-            .output()?;
+        command::output_lines(&self.list_explicit_packages_command)
+    }
+    
+    pub fn all_packages(&self) -> anyhow::Result<Vec<String>> {
+        command::output_lines(&self.list_all_packages_command)
+    }
+    
+    /// Get every package owned configuration file the user has modified.
+    pub fn modified_files(&self) -> anyhow::Result<Vec<String>> {
+        let list_modified_files_command = self.list_modified_files_command
+            .as_ref()
+            .ok_or_else(|| anyhow!("Listing modified files is not supported by the \"{}\" specification", self.binary_name))?;
         
-        // Theoretically this should be safe unless the package
-        // manager's output is something weird like non UTF-8.
-        let stdout = String::from_utf8(output.stdout)?;
+        command::output_lines(list_modified_files_command)
+    }
+    
+    /// Get every file owned by an installed package.
+    pub fn owned_files(&self) -> anyhow::Result<Vec<String>> {
+        let list_owned_files_command = self.list_owned_files_command
+            .as_ref()
+            .ok_or_else(|| anyhow!("Listing the files owned by packages is not supported by the \"{}\" specification", self.binary_name))?;
         
-        Ok(stdout.split_whitespace().map(|x| x.to_owned()).collect())
+        command::output_lines(list_owned_files_command)
     }
     
-    pub fn all_packages(&self) -> anyhow::Result<Vec<String>> {
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(&self.list_all_packages_command)
-            .output()?;
+    /// Returns true if `package` can be installed from the package manager's repositories, always
+    /// true without an `info_command`.
+    pub fn is_available(&self, package: &str) -> anyhow::Result<bool> {
+        let Some(info_command) = &self.info_command else {
+            return Ok(true)
+        };
+        
+        command::succeeds(&info_command.replace("{}", &command::shell_quote(package)))
+    }
+    
+    /// Get the names of every package in the repositories matching the regular expression
+    /// `pattern`, empty without a `search_command`.
+    pub fn search(&self, pattern: &str) -> anyhow::Result<Vec<String>> {
+        let Some(search_command) = &self.search_command else {
+            return Ok(vec![])
+        };
+        
+        command::output_lines(&search_command.replace("{}", &command::shell_quote(pattern)))
+    }
+    
+    /// Find packages in the repositories with a name similar to `package`.
+    /// 
+    /// Candidates are every package containing `package` or sharing its first few characters,
+    /// ranked by edit distance.
+    pub fn suggestions(&self, package: &str) -> anyhow::Result<Vec<String>> {
+        let prefix: String = package.chars().take(3).collect();
+        
+        let mut candidates: Vec<String> = self.search(&regex::escape(package))?;
+        candidates.extend(self.search(&format!("^{}", regex::escape(&prefix)))?);
+        candidates.sort();
+        candidates.dedup();
         
-        let stdout = String::from_utf8(output.stdout)?;
+        // Anything further away than this is more likely a different package than a typo.
+        let max_distance = (package.len() / 3).max(2);
         
-        Ok(stdout.split_whitespace().map(|x| x.to_owned()).collect())
+        let mut suggestions: Vec<(usize, String)> = candidates
+            .into_iter()
+            .map(|candidate| (strsim::levenshtein(package, &candidate), candidate))
+            .filter(|(distance, candidate)| *distance <= max_distance || candidate.contains(package))
+            .collect();
+        
+        suggestions.sort();
+        
+        Ok(suggestions
+            .into_iter()
+            .take(MAX_SUGGESTIONS)
+            .map(|(_, candidate)| candidate)
+            .collect())
+    }
+    
+    /// Get the members of the package group `group`, empty if `group` isn't a group or the
+    /// specification has no `list_group_members_command`.
+    pub fn group_members(&self, group: &str) -> anyhow::Result<Vec<String>> {
+        let Some(list_group_members_command) = &self.list_group_members_command else {
+            return Ok(vec![])
+        };
+        
+        command::output_lines(&list_group_members_command.replace("{}", &command::shell_quote(group)))
+    }
+    
+    /// Replace every package group in `packages` with its members, removing duplicates.
+    /// 
+    /// Group members are installed (and listed as explicit packages) individually, so anything
+    /// comparing the configuration against the system has to use the expanded list. Installed
+    /// packages can't be groups so the package manager is only asked about the rest.
+    pub fn expand_groups(&self, packages: &[&str]) -> anyhow::Result<Vec<String>> {
+        let installed_packages: HashSet<String> = self.all_packages()?.into_iter().collect();
+        
+        let mut seen = HashSet::new();
+        let mut expanded = vec![];
+        
+        for package in packages {
+            let mut members = vec![];
+            if !installed_packages.contains(*package) {
+                members = self.group_members(package)?;
+            }
+            
+            if members.is_empty() {
+                members.push(package.to_string());
+            }
+            
+            for member in members {
+                if seen.insert(member.clone()) {
+                    expanded.push(member);
+                }
+            }
+        }
+        
+        Ok(expanded)
+    }
+    
+    /// Check every package in `packages` against the installed packages and the repositories,
+    /// returning the ones that can't be installed along with suggestions.
+    /// 
+    /// Specifications without an `info_command` can't tell, no package is unknown to them.
+    pub fn unknown_packages(&self, packages: &[&str]) -> anyhow::Result<Vec<UnknownPackage>> {
+        if self.info_command.is_none() {
+            return Ok(vec![])
+        }
+        
+        let installed_packages: HashSet<String> = self.all_packages()?.into_iter().collect();
+        
+        let mut unknown_packages = vec![];
+        for package in packages {
+            if installed_packages.contains(*package) || self.is_available(package)? {
+                continue;
+            }
+            
+            unknown_packages.push(UnknownPackage {
+                name: package.to_string(),
+                suggestions: self.suggestions(package)?
+            });
+        }
+        
+        Ok(unknown_packages)
     }
     
     /// Install a list of packages using the PackageManager specification
-    pub fn install(&self, packages: Vec<&str>) -> anyhow::Result<()> {
+    /// 
+    /// Packages that aren't in the repositories are installed with `unprivileged_install_command`
+    /// as `build_user` when the specification supports it. How the packages are split into
+    /// commands depends on `strategy`, failing packages end up in the report instead of an error.
+    pub fn install(&self, 
+                   packages: Vec<&str>, 
+                   build_user: Option<&str>, 
+                   strategy: &InstallStrategy) -> anyhow::Result<InstallReport> {
         // Filter out already installed packages
         let installed_packages: HashSet<String> = self.all_packages()?.into_iter().collect();
         
+        let mut packages: Vec<&str> = packages
+            .into_iter()
+            .filter(|package| !installed_packages.contains(*package))
+            .collect();
+        
+        let mut report = InstallReport::default();
+        
+        if packages.is_empty() {
+            log::info!("No new packages.");
+            return Ok(report)
+        }
+        
+        if let (Some(unprivileged_install_command), Some(list_repository_packages_command)) = 
+            (&self.unprivileged_install_command, &self.list_repository_packages_command) {
+            let repository_packages: HashSet<String> = command::output_lines(list_repository_packages_command)?.into_iter().collect();
+            
+            let unprivileged_packages: Vec<&str>;
+            (packages, unprivileged_packages) = packages
+                .into_iter()
+                .partition(|package| repository_packages.contains(*package));
+            
+            if !unprivileged_packages.is_empty() {
+                match build_user {
+                    Some(build_user) => {
+                        log::info!("Building {} package(s) as \"{}\": {}.", unprivileged_packages.len(), build_user, unprivileged_packages.join(","));
+                        report.extend(strategy.run(&unprivileged_packages, |batch| {
+                            command::run_as(&command::fill_template(unprivileged_install_command, batch), build_user)
+                        }));
+                    },
+                    None => report.failed.extend(unprivileged_packages.iter().map(|package| (
+                        package.to_string(),
+                        String::from("has to be built as an unprivileged user, set `build_user` in the configuration")
+                    )))
+                }
+            }
+        }
+        
+        if !packages.is_empty() {
+            log::info!("Installing {} package(s): {}.", packages.len(), packages.join(","));
+            report.extend(strategy.run(&packages, |batch| self.install_batch(batch)));
+        }
+        
+        Ok(report)
+    }
+    
+    /// Download `packages` and their dependencies into `cache`.
+    pub fn download(&self, packages: &[&str], cache: &Path) -> anyhow::Result<()> {
+        let download_command = self.download_command
+            .as_ref()
+            .ok_or_else(|| anyhow!("The \"{}\" specification can't download packages", self.binary_name))?;
+        
+        let cache = cache.to_string_lossy();
+        command::run(&command::fill_template(&command::fill_quoted_placeholders(download_command, &[("cache", &cache)]), packages))
+    }
+    
+    /// Download `version` of `package` into `cache`.
+    pub fn download_version(&self, package: &str, version: &str, cache: &Path) -> anyhow::Result<()> {
+        let download_version_command = self.download_version_command
+            .as_ref()
+            .ok_or_else(|| anyhow!("The \"{}\" specification can't download specific package versions", self.binary_name))?;
+        
+        let cache = cache.to_string_lossy();
+        let download_version_command = command::fill_placeholders(download_version_command, &[("name", package), ("version", version)])?;
+        command::run(&command::fill_quoted_placeholders(&download_version_command, &[("cache", &cache)]))
+    }
+    
+    /// Same as `install` but the packages are installed from `cache` with `offline_install_command`.
+    pub fn install_offline(&self, 
+                           packages: Vec<&str>, 
+                           cache: &Path, 
+                           strategy: &InstallStrategy) -> anyhow::Result<InstallReport> {
+        let offline_install_command = self.offline_install_command
+            .as_ref()
+            .ok_or_else(|| anyhow!("The \"{}\" specification can't install packages offline", self.binary_name))?;
+        
+        let installed_packages: HashSet<String> = self.all_packages()?.into_iter().collect();
         let packages: Vec<&str> = packages
             .into_iter()
             .filter(|package| !installed_packages.contains(*package))
@@ -82,11 +514,41 @@ impl PackageManager {
         
         if packages.is_empty() {
             log::info!("No new packages.");
-            return Ok(())
+            return Ok(InstallReport::default())
+        }
+        
+        log::info!("Installing {} package(s) from \"{}\": {}.", packages.len(), cache.display(), packages.join(","));
+        
+        let cache = cache.to_string_lossy();
+        let takes_packages = offline_install_command.contains("{}");
+        let offline_install_command = command::fill_quoted_placeholders(offline_install_command, &[("cache", &cache)]);
+        
+        if takes_packages {
+            return Ok(strategy.run(&packages, |batch| command::run(&command::fill_template(&offline_install_command, batch))));
         }
         
-        log::info!("Installing {} package(s): {}.", packages.len(), packages.join(","));
+        // Batches would only install the whole cache again.
+        let result = command::run(&offline_install_command);
+        let installed_packages: HashSet<String> = self.all_packages()?.into_iter().collect();
+        
+        let mut report = InstallReport::default();
+        for package in packages {
+            if installed_packages.contains(package) {
+                report.installed.push(package.to_owned());
+            } else {
+                let reason = match &result {
+                    Ok(()) => String::from("not in the package cache, run `goat fetch` first"),
+                    Err(e) => e.to_string()
+                };
+                report.failed.push((package.to_owned(), reason));
+            }
+        }
         
+        Ok(report)
+    }
+    
+    /// Install `packages` with a single `install_command`.
+    fn install_batch(&self, packages: &[&str]) -> anyhow::Result<()> {
         // Join all packages into a single space-separated string
         let packages = packages.join(" ");
 
@@ -109,16 +571,183 @@ impl PackageManager {
         Ok(())
     }
 
-    pub fn remove_unneeded_packages(&self, explicitly_needed_packages: Vec<&str>) -> anyhow::Result<()> {
+    /// Get the installed version of every package.
+    pub fn versions(&self) -> anyhow::Result<HashMap<String, String>> {
+        let list_versions_command = self.list_versions_command
+            .as_ref()
+            .ok_or_else(|| anyhow!("The \"{}\" specification can't list package versions", self.binary_name))?;
+        
+        Ok(command::output_lines(list_versions_command)?
+            .into_iter()
+            .filter_map(|line| {
+                let (name, version) = line.split_once(char::is_whitespace)?;
+                Some((name.to_owned(), version.trim().to_owned()))
+            })
+            .collect())
+    }
+    
+    /// Install `version` of `package`, replacing any other installed version.
+    pub fn install_version(&self, package: &str, version: &str) -> anyhow::Result<()> {
+        let install_version_command = self.install_version_command
+            .as_ref()
+            .ok_or_else(|| anyhow!("The \"{}\" specification can't install specific package versions", self.binary_name))?;
+        
+        log::info!("Installing \"{}\" at version {}.", package, version);
+        
+        command::run(&command::fill_placeholders(install_version_command, &[("name", package), ("version", version)])?)
+    }
+    
+    /// Keep `package` from being upgraded.
+    pub fn hold(&self, package: &str) -> anyhow::Result<()> {
+        let hold_command = self.hold_command
+            .as_ref()
+            .ok_or_else(|| anyhow!("The \"{}\" specification can't hold packages", self.binary_name))?;
+        
+        command::run(&command::fill_placeholders(hold_command, &[("name", package)])?)
+    }
+    
+    /// Let `package` be upgraded again after `hold`. Specifications that can't only warn, the
+    /// package has to be released by hand.
+    pub fn unhold(&self, package: &str) -> anyhow::Result<()> {
+        let Some(unhold_command) = &self.unhold_command else {
+            log::warn!("The \"{}\" specification can't release held packages, \"{}\" stays held.", self.binary_name, package);
+            return Ok(())
+        };
+        
+        log::info!("Releasing the hold on \"{}\".", package);
+        
+        command::run(&command::fill_placeholders(unhold_command, &[("name", package)])?)
+    }
+    
+    /// The file repositories are rendered into, `None` if the specification doesn't support
+    /// repositories.
+    pub fn repositories_file(&self) -> Option<&str> {
+        self.repositories_file.as_deref()
+    }
+    
+    /// Render a single repository using `repository_template`.
+    pub fn render_repository(&self, repository: &Repository) -> anyhow::Result<String> {
+        let template = self.repository_template
+            .as_ref()
+            .ok_or_else(|| anyhow!("The \"{}\" specification can't render repositories", self.binary_name))?;
+        
+        let mut rendered = vec![];
+        
+        for line in template.split('\n') {
+            let line = line
+                .replace("{name}", &repository.name)
+                .replace("{key}", repository.key.as_deref().unwrap_or(""))
+                .replace("{priority}", &repository.priority.to_string());
+            
+            if line.contains("{url}") {
+                rendered.extend(repository.urls.iter().map(|url| line.replace("{url}", url)));
+            } else {
+                rendered.push(line);
+            }
+        }
+        
+        Ok(rendered.join("\n"))
+    }
+    
+    /// Trust the signing key of `repository`, if it has one.
+    pub fn import_key(&self, repository: &Repository) -> anyhow::Result<()> {
+        let (Some(import_key_command), Some(key)) = (&self.import_key_command, &repository.key) else {
+            return Ok(())
+        };
+        
+        log::info!("Importing the signing key of repository \"{}\".", repository.name);
+        
+        command::run(&command::fill_quoted_placeholders(import_key_command, &[("key", key), ("name", &repository.name)]))
+    }
+    
+    /// Update and upgrade the whole system.
+    pub fn full_system_update(&self) -> anyhow::Result<()> {
+        log::info!("Upgrading the whole system.");
+        
+        command::run(&self.full_system_update_command)
+    }
+    
+    /// Let the package manager pick up changes to `repositories_file`.
+    pub fn refresh_repositories(&self) -> anyhow::Result<()> {
+        match &self.refresh_repositories_command {
+            Some(refresh_repositories_command) => command::run(refresh_repositories_command),
+            None => Ok(())
+        }
+    }
+    
+    /// Mark every package in `packages` that is installed as a dependency as explicitly installed,
+    /// so the package manager's database agrees with the configuration.
+    pub fn mark_as_explicit(&self, packages: &[&str]) -> anyhow::Result<()> {
+        let Some(mark_as_explicit_command) = &self.mark_as_explicit_command else {
+            return Ok(())
+        };
+        
+        let installed_packages: HashSet<String> = self.all_packages()?.into_iter().collect();
+        let explicit_packages: HashSet<String> = self.explicit_packages()?.into_iter().collect();
+        
+        let dependency_packages: Vec<&str> = packages
+            .iter()
+            .copied()
+            .filter(|package| installed_packages.contains(*package) && !explicit_packages.contains(*package))
+            .collect();
+        
+        if dependency_packages.is_empty() {
+            return Ok(())
+        }
+        
+        log::info!("Marking {} package(s) as explicitly installed: {}.", dependency_packages.len(), dependency_packages.join(","));
+        
+        command::run(&command::fill_template(mark_as_explicit_command, &dependency_packages))
+    }
+    
+    /// Mark `packages` as installed as a dependency and return the ones nothing depends on.
+    /// 
+    /// Returns `None` when the package manager specification doesn't support this.
+    fn demote_packages<'a>(&self, packages: &[&'a str]) -> anyhow::Result<Option<Vec<&'a str>>> {
+        let (Some(mark_as_dependency_command), Some(list_orphans_command)) = 
+            (&self.mark_as_dependency_command, &self.list_orphans_command) else {
+            return Ok(None)
+        };
+        
+        command::run(&command::fill_template(mark_as_dependency_command, packages))?;
+        
+        let orphans: HashSet<String> = command::output_lines(list_orphans_command)?.into_iter().collect();
+        
+        let (orphans, required): (Vec<&str>, Vec<&str>) = packages
+            .iter()
+            .partition(|package| orphans.contains(**package));
+        
+        if !required.is_empty() {
+            log::info!("Keeping {} package(s) as dependencies of other packages: {}.", required.len(), required.join(","));
+        }
+        
+        Ok(Some(orphans))
+    }
+    
+    /// Remove every explicitly installed package not in `explicitly_needed_packages`.
+    /// 
+    /// With `demote` the packages are marked as dependencies first and only the ones nothing else
+    /// depends on are removed, instead of failing the removal.
+    pub fn remove_unneeded_packages(&self, explicitly_needed_packages: Vec<&str>, demote: bool) -> anyhow::Result<()> {
         let explicitly_installed_packages: HashSet<String> = self.explicit_packages()?.into_iter().collect();
 
         let needed: HashSet<&str> = explicitly_needed_packages.iter().copied().collect();
-        let unneeded_packages: Vec<&str> = explicitly_installed_packages
+        let mut unneeded_packages: Vec<&str> = explicitly_installed_packages
             .iter()
             .filter(|pkg| !needed.contains(pkg.as_str()))
             .map(|s| s.as_str())
             .collect();
         
+        if demote && !unneeded_packages.is_empty() {
+            match self.demote_packages(&unneeded_packages)? {
+                Some(orphans) => unneeded_packages = orphans,
+                None => log::warn!(
+                    "The \"{}\" specification can't mark packages as dependencies, removing them instead.",
+                    self.binary_name
+                )
+            }
+        }
+        
         if unneeded_packages.is_empty() {
             return Ok(())
         }
@@ -147,3 +776,27 @@ impl PackageManager {
         Ok(())
     }
 }
+
+/// Returns true if the installed version `installed` satisfies the pinned version `pinned`.
+/// 
+/// The pin may leave out the package release (`6.9.1` matches `6.9.1-2`).
+pub fn version_matches(installed: &str, pinned: &str) -> bool {
+    installed == pinned || installed.strip_prefix(pinned).is_some_and(|rest| rest.starts_with('-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned_versions_match_with_or_without_release() {
+        assert!(version_matches("6.9.1-2", "6.9.1-2"));
+        assert!(version_matches("6.9.1-2", "6.9.1"));
+        assert!(version_matches("1:2.0-1", "1:2.0"));
+
+        assert!(!version_matches("6.9.10-1", "6.9.1"));
+        assert!(!version_matches("6.9.1-2", "6.9.1-1"));
+        assert!(!version_matches("6.9.1", "6.9.1-1"));
+        assert!(!version_matches("6.9.1.1-1", "6.9.1"));
+    }
+}
@@ -0,0 +1,50 @@
+use crate::goat::Goat;
+// plan.rs
+//
+// All logic related to the `plan` subcommand should be placed here.
+
+/// Prefix of the sources in `Config.sources` that are roles enabled through `profiles`.
+const PROFILE_SOURCE_PREFIX: &str = "profile:";
+
+impl Goat {
+    /// Items each enabled profile adds to the configuration, in the order the profiles were
+    /// enabled.
+    ///
+    /// Items the configuration already had before a profile was merged count for the
+    /// configuration, not the profile.
+    pub fn profile_contributions(&self) -> Vec<(String, Vec<String>)> {
+        self.config.profiles
+            .iter()
+            .map(|profile| {
+                let items = self.config.sources
+                    .iter()
+                    .filter(|(_, source)| source.strip_prefix(PROFILE_SOURCE_PREFIX) == Some(profile.as_str()))
+                    .map(|(item, _)| item.clone())
+                    .collect();
+
+                (profile.clone(), items)
+            })
+            .collect()
+    }
+
+    /// Print what each enabled profile contributes, followed by what a sync would change.
+    pub fn plan(&self) -> anyhow::Result<()> {
+        if self.config.profiles.is_empty() {
+            println!("No profiles enabled.");
+        }
+
+        for (profile, items) in self.profile_contributions() {
+            println!("Profile \"{}\":", profile);
+
+            if items.is_empty() {
+                println!("  (nothing the configuration doesn't already have)");
+            }
+            for item in items {
+                println!("  + {}", item);
+            }
+        }
+
+        println!();
+        self.status()?.print(false)
+    }
+}
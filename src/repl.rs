@@ -0,0 +1,239 @@
+use anyhow::anyhow;
+use goat_lua::GoatLua;
+use mlua::{MultiValue, Table, Value};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use crate::config::{self, Config};
+use crate::goat::Goat;
+use crate::lua_writer::{is_identifier, quote};
+// repl.rs
+//
+// All logic related to the `repl` subcommand should be placed here.
+
+/// Tables nested deeper than this are printed as `{...}`.
+const MAX_PRINT_DEPTH: usize = 8;
+
+const PROMPT: &str = "goat> ";
+const CONTINUATION_PROMPT: &str = "  ... ";
+
+/// Completes global names and fields of tables such as `goat.` or `config.`.
+struct ReplHelper {
+    globals: Table
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        let word_start = before
+            .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == ':'))
+            .map(|index| index + 1)
+            .unwrap_or(0);
+        let word = &before[word_start..];
+
+        // `goat.pro` completes `pro` inside the `goat` table.
+        let (path, prefix) = match word.rfind(['.', ':']) {
+            Some(index) => (&word[..index], &word[index + 1..]),
+            None => ("", word)
+        };
+
+        let mut table = self.globals.clone();
+        for key in path.split(['.', ':']).filter(|key| !key.is_empty()) {
+            match table.get::<Value>(key) {
+                Ok(Value::Table(inner)) => table = inner,
+                _ => return Ok((pos, vec![]))
+            }
+        }
+
+        let mut candidates: Vec<String> = table.pairs::<Value, Value>()
+            .flatten()
+            .filter_map(|(key, value)| match key {
+                Value::String(key) => {
+                    let key = key.to_string_lossy();
+                    let suffix = if value.is_function() { "(" } else { "" };
+                    (key.starts_with(prefix) && is_identifier(&key)).then(|| format!("{}{}", key, suffix))
+                },
+                _ => None
+            })
+            .collect();
+        candidates.sort();
+
+        Ok((pos - prefix.len(), candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// Write `value` the way the repl shows results: tables as lua source, everything that can't be
+/// written as lua (functions, userdata, ...) by its type.
+fn write_value(out: &mut String, value: &Value, depth: usize) {
+    match value {
+        Value::String(string) => out.push_str(&quote(&string.to_string_lossy())),
+        Value::Table(table) if depth >= MAX_PRINT_DEPTH => {
+            out.push_str(if table.is_empty() { "{}" } else { "{...}" })
+        },
+        Value::Table(table) => {
+            let mut pairs: Vec<(Value, Value)> = table.pairs::<Value, Value>().flatten().collect();
+            if pairs.is_empty() {
+                out.push_str("{}");
+                return;
+            }
+
+            let length = table.raw_len();
+            // The sequence part goes first in order, keyed fields follow sorted by key.
+            pairs.sort_by_key(|(key, _)| match key {
+                Value::Integer(index) if *index >= 1 && (*index as usize) <= length => (0, *index, String::new()),
+                key => (1, 0, key.to_string().unwrap_or_default())
+            });
+
+            let indent = "    ".repeat(depth + 1);
+            out.push_str("{\n");
+
+            for (key, value) in pairs {
+                out.push_str(&indent);
+
+                match &key {
+                    Value::Integer(index) if *index >= 1 && (*index as usize) <= length => {},
+                    Value::String(key) if is_identifier(&key.to_string_lossy()) => {
+                        out.push_str(&format!("{} = ", key.to_string_lossy()));
+                    },
+                    key => {
+                        out.push('[');
+                        write_value(out, key, depth + 1);
+                        out.push_str("] = ");
+                    }
+                }
+
+                write_value(out, &value, depth + 1);
+                out.push_str(",\n");
+            }
+
+            out.push_str(&"    ".repeat(depth));
+            out.push('}');
+        },
+        Value::Nil | Value::Boolean(_) | Value::Integer(_) | Value::Number(_) => {
+            out.push_str(&value.to_string().unwrap_or_default())
+        },
+        value => out.push_str(&format!("<{}>", value.type_name()))
+    }
+}
+
+/// Run `source` as an expression, or as a statement if it isn't one, and return its results.
+///
+/// `Ok(None)` means the input is incomplete, like an unterminated `function ... end`.
+fn run_line(lua: &GoatLua, source: &str) -> anyhow::Result<Option<MultiValue>> {
+    if let Ok(function) = lua.lua.load(format!("return {}", source)).set_name("=repl").into_function() {
+        return function.call::<MultiValue>(()).map(Some).map_err(|e| anyhow!("{}", e));
+    }
+
+    match lua.lua.load(source).set_name("=repl").into_function() {
+        Ok(function) => function.call::<MultiValue>(()).map(Some).map_err(|e| anyhow!("{}", e)),
+        Err(mlua::Error::SyntaxError { incomplete_input: true, .. }) => Ok(None),
+        Err(e) => Err(anyhow!("{}", e))
+    }
+}
+
+impl Goat {
+    /// Start an interactive lua session with the `goat` module, `require` able to load files from
+    /// the configuration directory and the evaluated configuration as `config`.
+    ///
+    /// Configuration errors are only logged so the session can be used to debug them.
+    pub fn repl(&self) -> anyhow::Result<()> {
+        let configuration_directory = &self.directories["configuration_directory"];
+        let config_file = configuration_directory.join(&self.files["config_file"]);
+
+        let lua = GoatLua::create()?;
+
+        match Config::evaluate(&lua, &config_file, config::current_hostname().as_deref(), &[]) {
+            Ok((config, _)) => {
+                if let Err(e) = Config::from_table(&config) {
+                    log::warn!("The configuration is invalid: {}", e);
+                }
+
+                lua.lua.globals().set("config", config).map_err(|e| anyhow!("{}", e))?;
+            },
+            Err(e) => {
+                log::warn!("Failed to evaluate the configuration, `config` is not available: {}", e);
+                Config::extend_package_path(&lua, configuration_directory)?;
+            }
+        }
+
+        let mut editor: Editor<ReplHelper, DefaultHistory> = Editor::new().map_err(|e| anyhow!("{}", e))?;
+        editor.set_helper(Some(ReplHelper {
+            globals: lua.lua.globals()
+        }));
+
+        let history_file = self.directories["cache_directory"].join(&self.files["repl_history_file"]);
+        // There is no history on the first run.
+        let _ = editor.load_history(&history_file);
+
+        let mut source = String::new();
+
+        loop {
+            let prompt = if source.is_empty() { PROMPT } else { CONTINUATION_PROMPT };
+
+            let line = match editor.readline(prompt) {
+                Ok(line) => line,
+                // Ctrl-C drops the current input, Ctrl-D quits.
+                Err(ReadlineError::Interrupted) => {
+                    source.clear();
+                    continue;
+                },
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(anyhow!("{}", e))
+            };
+
+            if !source.is_empty() {
+                source.push('\n');
+            }
+            source.push_str(&line);
+
+            if source.trim().is_empty() {
+                source.clear();
+                continue;
+            }
+
+            match run_line(&lua, &source) {
+                Ok(None) => continue,
+                Ok(Some(values)) => {
+                    let mut out = String::new();
+
+                    for (index, value) in values.iter().enumerate() {
+                        if index > 0 {
+                            out.push('\t');
+                        }
+                        write_value(&mut out, value, 0);
+                    }
+
+                    if !out.is_empty() {
+                        println!("{}", out);
+                    }
+                },
+                Err(e) => eprintln!("{}", e)
+            }
+
+            editor.add_history_entry(source.as_str()).map_err(|e| anyhow!("{}", e))?;
+            source.clear();
+        }
+
+        if let Err(e) = editor.save_history(&history_file) {
+            log::warn!("Failed to save the repl history: {}", e);
+        }
+
+        Ok(())
+    }
+}
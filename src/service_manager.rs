@@ -1,14 +1,27 @@
 use goat_lua::GoatLua;
 use anyhow::anyhow;
-use goat_lua_macro::FromLuaFile;
+use goat_lua_macro::{FromLuaFile, LuaTypes};
+use serde::Serialize;
+use crate::command;
 
 // Time to unify systemd and openrc...
 
-#[derive(FromLuaFile)]
+#[derive(FromLuaFile, Serialize, LuaTypes)]
 pub struct ServiceManager {
     /// This is the name of the application that the service manager relies on. This will not be
     /// used for commands but to confirm the existence of this specific service manager.
     pub binary_name: String,
     /// The command to run to reload the hostname.
-    pub hostname_reload_command: String
+    pub hostname_reload_command: String,
+    /// Get a list of services enabled at boot.
+    /// 
+    /// ex: `systemctl list-unit-files --state=enabled --type=service --no-legend | cut -d ' ' -f1`
+    list_enabled_services_command: String
+}
+
+impl ServiceManager {
+    /// Get a list of services enabled at boot.
+    pub fn enabled_services(&self) -> anyhow::Result<Vec<String>> {
+        command::output_lines(&self.list_enabled_services_command)
+    }
 }
@@ -1,17 +1,69 @@
 use std::fs;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use anyhow::anyhow;
 use mlua::ObjectLike;
+use serde::{Deserialize, Serialize};
 use goat_lua::GoatLua;
+use crate::events;
 use crate::goat::Goat;
+use crate::package_manager::version_matches;
 
 pub enum StageResult {
     Done,
-    Skipped
+    Skipped,
+    
+    /// The stage ran but reports on each item it handled, some of which may have failed.
+    Report(StageReport)
+}
+
+/// An item a stage didn't apply and why.
+#[derive(Serialize, Deserialize)]
+pub struct ReportEntry {
+    pub item: String,
+    pub reason: String
+}
+
+/// What a stage did to each of its items, for stages that keep going when a single item fails.
+#[derive(Serialize, Deserialize, Default)]
+pub struct StageReport {
+    pub done: Vec<String>,
+    pub failed: Vec<ReportEntry>,
+    pub skipped: Vec<ReportEntry>
+}
+
+/// A single item whose current value differs from the configured one.
+#[derive(Serialize)]
+pub struct Change {
+    pub item: String,
+    pub current: String,
+    pub expected: String
+}
+
+/// The difference between the running system and the configuration for a single stage.
+///
+/// - `missing`: items the configuration wants but the system doesn't have.
+/// - `extra`: items the system has but the configuration doesn't mention.
+/// - `changed`: items both sides have but with different values.
+#[derive(Serialize, Default)]
+pub struct StageDiff {
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+    pub changed: Vec<Change>
+}
+
+impl StageDiff {
+    /// Returns true if applying the stage would have no effect.
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.changed.is_empty()
+    }
 }
 
 /// Seperating each system management layer as a stage allows for easy debugging and modularity.
-pub trait Stage {
+/// 
+/// Stages are applied in dependency order (see `order.rs`), stages that are `parallel_safe` and
+/// don't depend on each other may be applied at the same time.
+pub trait Stage: Send + Sync {
     /// The stage's name.
     /// 
     /// This is used for debugging and to inform the user about which stage specifically failed,
@@ -20,11 +72,78 @@ pub trait Stage {
     
     /// Apply the given stage to the system.
     fn apply(&self, goat: &Goat) -> anyhow::Result<StageResult>;
+
+    /// Compare the system against the configuration without modifying anything.
+    ///
+    /// Stages that have no way of knowing the current system state report no drift.
+    fn diff(&self, _goat: &Goat) -> anyhow::Result<StageDiff> {
+        Ok(StageDiff::default())
+    }
+    
+    /// Names of the stages that have to be applied before this one.
+    fn after(&self) -> Vec<String> {
+        vec![]
+    }
+    
+    /// Names of the stages that have to be applied after this one.
+    fn before(&self) -> Vec<String> {
+        vec![]
+    }
+    
+    /// Whether the stage can be applied on its own thread next to other stages, it must not
+    /// touch anything another stage might (the package manager for example).
+    fn parallel_safe(&self) -> bool {
+        false
+    }
 }
 
 /// A custom stage based on a lua file.
+/// 
+/// The file can order the stage with `stage.after = { "Packages" }` and `stage.before = { ... }`
+/// and let it run next to other stages with `stage.parallel = true`.
+/// 
+/// The file is evaluated once when the stage is created, `stage.diff` and `stage.apply` run in
+/// that same lua state. Every command listing the stages (`goat status`, `goat plan`, ...)
+/// evaluates it, so code outside of those functions shouldn't change the system.
 pub struct CustomStage {
-    pub path: PathBuf
+    pub path: PathBuf,
+    pub after: Vec<String>,
+    pub before: Vec<String>,
+    pub parallel: bool,
+    
+    /// The runtime the file was evaluated in, it has to outlive `stage`.
+    lua: GoatLua,
+    stage: mlua::Table
+}
+
+impl CustomStage {
+    /// Load the custom stage at `path`, reading its ordering.
+    pub fn new(path: PathBuf) -> anyhow::Result<Self> {
+        let lua = GoatLua::create()?;
+        events::redirect_print(&lua.lua)?;
+        lua.lua.load(&*path).exec().map_err(|e| anyhow!("{}", e))?;
+        let stage = lua.lua.globals().get::<mlua::Table>("stage")
+            .map_err(|e| anyhow!("\"{}\" doesn't set a stage table: {}", path.display(), e))?;
+        
+        let names = |key: &str| -> anyhow::Result<Vec<String>> {
+            Ok(stage.get::<Option<Vec<String>>>(key)
+                .map_err(|e| anyhow!("Invalid stage.{} in \"{}\": {}", key, path.display(), e))?
+                .unwrap_or_default())
+        };
+        
+        let after = names("after")?;
+        let before = names("before")?;
+        let parallel = stage.get::<Option<bool>>("parallel").map_err(|e| anyhow!("{}", e))?.unwrap_or(false);
+        
+        Ok(CustomStage {
+            path,
+            after,
+            before,
+            parallel,
+            lua,
+            stage
+        })
+    }
 }
 
 impl Stage for CustomStage {
@@ -33,15 +152,62 @@ impl Stage for CustomStage {
         self.path.file_name().unwrap().to_string_lossy().to_string()
     }
     
+    /// `stage.apply` is called with the stage context, see `Goat::with_stage_context`.
     fn apply(&self, goat: &Goat) -> anyhow::Result<StageResult> {
-        let lua = GoatLua::create()?;
-        lua.lua.load(&*self.path).exec().map_err(|e| anyhow::anyhow!("{}", e))?;
-        let globals = lua.lua.globals();
-        let stage = globals.get::<mlua::Table>("stage").map_err(|e| anyhow!("{}", e))?;
-        stage.call_function::<()>("apply", ()).map_err(|e| anyhow!("{}", e))?;
+        goat.with_stage_context(&self.lua, &self.name(), false, |ctx| self.stage.call_function::<()>("apply", ctx))?;
         
         Ok(StageResult::Done)
     }
+
+    /// Custom stages can optionally provide a `stage.diff` function returning a table in the form
+    /// `{ missing = { ... }, extra = { ... }, changed = { { item = "", current = "", expected = "" } } }`.
+    /// It is called with a read-only context, without `ctx.packages.install` and `ctx.run`.
+    fn diff(&self, goat: &Goat) -> anyhow::Result<StageDiff> {
+        if !self.stage.contains_key("diff").map_err(|e| anyhow!("{}", e))? {
+            return Ok(StageDiff::default());
+        }
+        
+        let result = goat.with_stage_context(&self.lua, &self.name(), true, |ctx| self.stage.call_function::<mlua::Table>("diff", ctx))?;
+        let list = |key: &str| -> anyhow::Result<Vec<String>> {
+            match result.get::<Option<mlua::Table>>(key).map_err(|e| anyhow!("{}", e))? {
+                Some(table) => table.sequence_values::<String>()
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| anyhow!("{}", e)),
+                None => Ok(vec![])
+            }
+        };
+        
+        let mut diff = StageDiff {
+            missing: list("missing")?,
+            extra: list("extra")?,
+            changed: vec![]
+        };
+        
+        if let Some(changed) = result.get::<Option<mlua::Table>>("changed").map_err(|e| anyhow!("{}", e))? {
+            for change in changed.sequence_values::<mlua::Table>() {
+                let change = change.map_err(|e| anyhow!("{}", e))?;
+                diff.changed.push(Change {
+                    item: change.get("item").map_err(|e| anyhow!("{}", e))?,
+                    current: change.get("current").map_err(|e| anyhow!("{}", e))?,
+                    expected: change.get("expected").map_err(|e| anyhow!("{}", e))?
+                });
+            }
+        }
+        
+        Ok(diff)
+    }
+    
+    fn after(&self) -> Vec<String> {
+        self.after.clone()
+    }
+    
+    fn before(&self) -> Vec<String> {
+        self.before.clone()
+    }
+    
+    fn parallel_safe(&self) -> bool {
+        self.parallel
+    }
 }
 
 /// Hostname stage.
@@ -49,6 +215,9 @@ impl Stage for CustomStage {
 /// Synchronize hostname to configuration hostname.
 pub struct Hostname {} impl Stage for Hostname {
     fn name(&self) -> String { String::from("Hostname") }
+    
+    fn parallel_safe(&self) -> bool { true }
+    
     fn apply(&self, goat: &Goat) -> anyhow::Result<StageResult> {
         let current_hostname = fs::read_to_string("/etc/hostname")
             .map_err(|e| anyhow!("{}", e))?
@@ -72,26 +241,387 @@ pub struct Hostname {} impl Stage for Hostname {
             Ok(StageResult::Skipped)
         }
     }
+    
+    fn diff(&self, goat: &Goat) -> anyhow::Result<StageDiff> {
+        let current_hostname = fs::read_to_string("/etc/hostname")
+            .map_err(|e| anyhow!("{}", e))?
+            .trim()
+            .to_owned();
+        
+        let mut diff = StageDiff::default();
+        
+        if current_hostname != goat.config.hostname {
+            diff.changed.push(Change {
+                item: String::from("hostname"),
+                current: current_hostname,
+                expected: goat.config.hostname.clone()
+            });
+        }
+        
+        Ok(diff)
+    }
+}
+
+/// Repository stage.
+/// 
+/// Render every configured repository into the package manager's repositories file and trust the
+/// signing keys of new ones. This runs before the package stage so every configured package can be
+/// found.
+pub struct Repositories {}
+
+impl Repositories {
+    /// The repositories file and what it should contain, `None` if there is nothing to manage.
+    /// 
+    /// Every repository is rendered on its own, the sections are returned along with the file.
+    fn render(goat: &Goat) -> anyhow::Result<Option<(PathBuf, String, Vec<String>)>> {
+        let Some(path) = goat.package_manager.repositories_file() else {
+            if !goat.config.repositories.is_empty() {
+                return Err(anyhow!("The \"{}\" specification doesn't support repositories", goat.package_manager.binary_name));
+            }
+            return Ok(None);
+        };
+        
+        let path = PathBuf::from(path);
+        
+        // Once the file exists it's kept around (empty) when every repository is removed.
+        if goat.config.repositories.is_empty() && !path.exists() {
+            return Ok(None);
+        }
+        
+        let sections = goat.config.repositories
+            .iter()
+            .map(|repository| goat.package_manager.render_repository(repository))
+            .collect::<anyhow::Result<Vec<String>>>()?;
+        
+        let mut contents = String::from("# Generated by goat from the `repositories` table, changes will be overwritten.\n\n");
+        contents.push_str(&sections.join("\n"));
+        if !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        
+        Ok(Some((path, contents, sections)))
+    }
+}
+
+impl Stage for Repositories {
+    fn name(&self) -> String { String::from("Repositories") }
+    fn apply(&self, goat: &Goat) -> anyhow::Result<StageResult> {
+        let Some((path, contents, sections)) = Repositories::render(goat)? else {
+            return Ok(StageResult::Skipped);
+        };
+        
+        let current = fs::read_to_string(&path).unwrap_or_default();
+        if current == contents {
+            return Ok(StageResult::Skipped);
+        }
+        
+        if goat.offline {
+            log::warn!("Repositories changed but can't be refreshed offline, run a sync with network access.");
+            return Ok(StageResult::Skipped);
+        }
+        
+        // Keys only have to be imported once, when the repository shows up for the first time.
+        for (repository, section) in goat.config.repositories.iter().zip(&sections) {
+            if !current.contains(section.as_str()) {
+                goat.package_manager.import_key(repository)?;
+            }
+        }
+        
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, contents).map_err(|e| anyhow!("Failed to write \"{}\": {}", path.display(), e))?;
+        
+        goat.package_manager.refresh_repositories()?;
+        
+        if goat.config.full_upgrade_on_repository_change {
+            goat.package_manager.full_system_update()?;
+        } else {
+            log::warn!("Repositories changed without a system upgrade, set `full_upgrade_on_repository_change = true` if the package manager needs one to use them.");
+        }
+        
+        Ok(StageResult::Done)
+    }
+    
+    fn diff(&self, goat: &Goat) -> anyhow::Result<StageDiff> {
+        let Some((path, contents, sections)) = Repositories::render(goat)? else {
+            return Ok(StageDiff::default());
+        };
+        
+        let current = fs::read_to_string(&path).unwrap_or_default();
+        let mut diff = StageDiff::default();
+        
+        for (repository, section) in goat.config.repositories.iter().zip(&sections) {
+            if !current.contains(section.as_str()) {
+                diff.missing.push(repository.name.clone());
+            }
+        }
+        
+        // Removed repositories and hand edits don't show up as a missing repository.
+        if diff.missing.is_empty() && current != contents {
+            diff.changed.push(Change {
+                item: path.display().to_string(),
+                current: String::from("modified"),
+                expected: String::from("generated from the configuration")
+            });
+        }
+        
+        Ok(diff)
+    }
 }
 
 /// Package stage.
 /// 
 /// Install packages and remove unneeded packages. This stage will only fail if the package manager
 /// functions return an error.
-pub struct Packages {} impl Stage for Packages {
+pub struct Packages {}
+
+impl Packages {
+    /// The configured packages with every package group replaced by its members, `None` if the
+    /// configuration doesn't manage packages.
+    pub fn configured(goat: &Goat) -> anyhow::Result<Option<Vec<String>>> {
+        let Some(packages) = &goat.config.packages else {
+            return Ok(None);
+        };
+        
+        let packages: Vec<&str> = packages.iter().map(|package| package.as_str()).collect();
+        
+        Ok(Some(goat.package_manager.expand_groups(&packages)?))
+    }
+    
+    /// Every pinned package whose installed version differs from the pin, as
+    /// `(name, installed version, pinned version)`. Packages that aren't installed are left out.
+    fn version_mismatches(goat: &Goat) -> anyhow::Result<Vec<(String, String, String)>> {
+        if goat.config.package_pins.iter().all(|pin| pin.version.is_none()) {
+            return Ok(vec![]);
+        }
+        
+        let versions = goat.package_manager.versions()?;
+        
+        Ok(goat.config.package_pins
+            .iter()
+            .filter_map(|pin| {
+                let pinned = pin.version.as_ref()?;
+                let installed = versions.get(&pin.name)?;
+                
+                (!version_matches(installed, pinned)).then(|| (pin.name.clone(), installed.clone(), pinned.clone()))
+            })
+            .collect())
+    }
+    
+    /// The packages held by the last sync, see `enforce_pins`.
+    fn held_packages(goat: &Goat) -> anyhow::Result<Vec<String>> {
+        let held_packages_file = goat.directories["cache_directory"].join(&goat.files["held_packages_file"]);
+        if !held_packages_file.exists() {
+            return Ok(vec![]);
+        }
+        
+        serde_json::from_str(&fs::read_to_string(&held_packages_file)?)
+            .map_err(|e| anyhow!("Invalid \"{}\": {}", held_packages_file.display(), e))
+    }
+    
+    /// Install every pinned package at its pinned version and hold the ones that ask for it.
+    /// 
+    /// Packages held by an earlier sync whose pin no longer asks for it are released again, goat
+    /// keeps track of its own holds so holds made by hand are left alone.
+    /// 
+    /// Failures are added to `report` instead of returned so one unavailable version doesn't stop
+    /// the rest of the stage, whatever is still wrong afterward gets reported.
+    fn enforce_pins(goat: &Goat, report: &mut StageReport) -> anyhow::Result<()> {
+        // Holding alone doesn't need the installed versions.
+        let versions = if goat.config.package_pins.iter().any(|pin| pin.version.is_some()) {
+            goat.package_manager.versions()?
+        } else {
+            HashMap::new()
+        };
+        
+        for pin in &goat.config.package_pins {
+            if let Some(version) = &pin.version 
+                && !versions.get(&pin.name).is_some_and(|installed| version_matches(installed, version)) {
+                // Offline the pinned version can only come from the package cache, which the
+                // offline install already went through.
+                let result = if goat.offline {
+                    Err(anyhow!("it wasn't installed from the package cache, run `goat fetch` first"))
+                } else {
+                    goat.package_manager.install_version(&pin.name, version)
+                };
+                
+                match result {
+                    Ok(()) => report.done.push(pin.name.clone()),
+                    Err(e) => report.failed.push(ReportEntry {
+                        item: pin.name.clone(),
+                        reason: format!("failed to install version {}: {}", version, e)
+                    })
+                }
+            }
+            
+            if pin.hold && let Err(e) = goat.package_manager.hold(&pin.name) {
+                report.failed.push(ReportEntry {
+                    item: pin.name.clone(),
+                    reason: format!("failed to hold: {}", e)
+                });
+            }
+        }
+        
+        let mut held_packages: Vec<String> = goat.config.package_pins
+            .iter()
+            .filter(|pin| pin.hold)
+            .map(|pin| pin.name.clone())
+            .collect();
+        
+        for package in Packages::held_packages(goat)? {
+            if held_packages.contains(&package) {
+                continue;
+            }
+            
+            // Kept in the list so the next sync tries again.
+            if let Err(e) = goat.package_manager.unhold(&package) {
+                report.failed.push(ReportEntry {
+                    item: package.clone(),
+                    reason: format!("failed to release the hold: {}", e)
+                });
+                held_packages.push(package);
+            }
+        }
+        
+        let held_packages_file = goat.directories["cache_directory"].join(&goat.files["held_packages_file"]);
+        fs::write(&held_packages_file, serde_json::to_string_pretty(&held_packages)?)
+            .map_err(|e| anyhow!("Failed to write \"{}\": {}", held_packages_file.display(), e))?;
+        
+        for (name, installed, pinned) in Packages::version_mismatches(goat)? {
+            // Don't report the same package twice when installing the version already failed.
+            if report.failed.iter().any(|entry| entry.item == name) {
+                continue;
+            }
+            
+            report.failed.push(ReportEntry {
+                item: name,
+                reason: format!("installed version {} doesn't match the pinned {}", installed, pinned)
+            });
+        }
+        
+        Ok(())
+    }
+}
+
+impl Stage for Packages {
     fn name(&self) -> String { String::from("Packages") }
+    
+    // Packages can come from the configured repositories.
+    fn after(&self) -> Vec<String> { vec![String::from("Repositories")] }
     fn apply(&self, goat: &Goat) -> anyhow::Result<StageResult> {
-        if let Some(packages) = &goat.config.packages {
+        if let Some(packages) = Packages::configured(goat)? {
             let packages: Vec<&str> = packages.iter().map(|package| package.as_str()).collect();
             
-            goat.package_manager.install(packages.clone())?;
-            goat.package_manager.remove_unneeded_packages(packages)?;
+            let mut report = StageReport::default();
             
-            Ok(StageResult::Done)
+            // A single unknown package would fail the whole install command, so leave them out
+            // and install everything else. They only fail the sync with `strict_packages`. The
+            // repositories can't be reached offline, there the package cache decides what can be
+            // installed.
+            let unknown_packages = if goat.offline {
+                vec![]
+            } else {
+                goat.package_manager.unknown_packages(&packages)?
+            };
+            for package in &unknown_packages {
+                if goat.config.strict_packages {
+                    report.skipped.push(ReportEntry {
+                        item: package.name.clone(),
+                        reason: format!("unknown package {}", package)
+                    });
+                } else {
+                    log::warn!("Not installing unknown package {}.", package);
+                }
+            }
+            
+            let installable_packages: Vec<&str> = packages
+                .iter()
+                .copied()
+                .filter(|package| !unknown_packages.iter().any(|unknown| unknown.name == *package))
+                .collect();
+            
+            // Packages with a pinned version are installed by `enforce_pins`.
+            let unpinned_packages: Vec<&str> = installable_packages
+                .iter()
+                .copied()
+                .filter(|package| !goat.config.package_pins.iter().any(|pin| pin.name == *package && pin.version.is_some()))
+                .collect();
+            
+            let install_report = if goat.offline {
+                goat.package_manager.install_offline(
+                    unpinned_packages,
+                    &goat.directories["package_cache_directory"],
+                    &goat.config.install_strategy
+                )?
+            } else {
+                goat.package_manager.install(
+                    unpinned_packages,
+                    goat.config.build_user.as_deref(),
+                    &goat.config.install_strategy
+                )?
+            };
+            report.done.extend(install_report.installed);
+            report.failed.extend(install_report.failed.into_iter().map(|(item, reason)| ReportEntry { item, reason }));
+            
+            Packages::enforce_pins(goat, &mut report)?;
+            goat.package_manager.mark_as_explicit(&installable_packages)?;
+            
+            // Packages custom stages installed through `ctx.packages.install` stay as well.
+            let stage_packages = goat.stage_managed_packages()?;
+            let mut needed_packages = packages;
+            needed_packages.extend(stage_packages.iter().map(|package| package.as_str()));
+            goat.package_manager.remove_unneeded_packages(needed_packages, goat.config.demote_unneeded_packages)?;
+            
+            Ok(StageResult::Report(report))
         } else {
             Ok(StageResult::Skipped)
         }
     }
+    
+    fn diff(&self, goat: &Goat) -> anyhow::Result<StageDiff> {
+        let Some(packages) = Packages::configured(goat)? else {
+            return Ok(StageDiff::default());
+        };
+        
+        let installed_packages: HashSet<String> = goat.package_manager.all_packages()?.into_iter().collect();
+        let stage_packages = goat.stage_managed_packages()?;
+        let configured_packages: HashSet<&str> = packages
+            .iter()
+            .chain(&stage_packages)
+            .map(|package| package.as_str())
+            .collect();
+        
+        let mut missing: Vec<String> = packages
+            .iter()
+            .filter(|package| !installed_packages.contains(*package))
+            .cloned()
+            .collect();
+        
+        let mut extra: Vec<String> = goat.package_manager
+            .explicit_packages()?
+            .into_iter()
+            .filter(|package| !configured_packages.contains(package.as_str()))
+            .collect();
+        
+        missing.sort();
+        extra.sort();
+        
+        let changed = Packages::version_mismatches(goat)?
+            .into_iter()
+            .map(|(name, installed, pinned)| Change {
+                item: name,
+                current: installed,
+                expected: pinned
+            })
+            .collect();
+        
+        Ok(StageDiff {
+            missing,
+            extra,
+            changed
+        })
+    }
 }
 
 /// Shortcut for creating an array of stages
@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::PoisonError;
+use anyhow::anyhow;
+use mlua::{Lua, Value};
+use goat_lua::GoatLua;
+use crate::command;
+use crate::goat::Goat;
+// stage_context.rs
+//
+// All logic related to the `ctx` table custom stages receive should be placed here.
+
+/// Convert a value serialized with serde_json into a lua value.
+fn json_to_lua(lua: &Lua, value: &serde_json::Value) -> mlua::Result<Value> {
+    Ok(match value {
+        serde_json::Value::Null => Value::Nil,
+        serde_json::Value::Bool(boolean) => Value::Boolean(*boolean),
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(integer) => Value::Integer(integer),
+            None => Value::Number(number.as_f64().unwrap_or_default())
+        },
+        serde_json::Value::String(string) => Value::String(lua.create_string(string)?),
+        serde_json::Value::Array(values) => {
+            let table = lua.create_table()?;
+            for value in values {
+                table.push(json_to_lua(lua, value)?)?;
+            }
+            Value::Table(table)
+        },
+        serde_json::Value::Object(fields) => {
+            let table = lua.create_table()?;
+            for (key, value) in fields {
+                table.set(key.as_str(), json_to_lua(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+impl Goat {
+    /// The packages each custom stage installed with `ctx.packages.install`, by stage name.
+    fn stage_packages(&self) -> anyhow::Result<BTreeMap<String, Vec<String>>> {
+        let stage_packages_file = self.directories["cache_directory"].join(&self.files["stage_packages_file"]);
+        if !stage_packages_file.exists() {
+            return Ok(BTreeMap::new());
+        }
+        
+        let contents = fs::read_to_string(&stage_packages_file)?;
+        serde_json::from_str(&contents)
+            .map_err(|e| anyhow!("Invalid \"{}\": {}", stage_packages_file.display(), e))
+    }
+    
+    /// Every package installed by a custom stage that still exists. The "Packages" stage keeps
+    /// these installed next to the configured `packages`.
+    pub fn stage_managed_packages(&self) -> anyhow::Result<Vec<String>> {
+        let mut stage_packages = self.stage_packages()?;
+        stage_packages.retain(|stage, _| self.directories["custom_stages"].join(stage).exists());
+        
+        Ok(stage_packages.into_values().flatten().collect())
+    }
+    
+    /// Remember that the custom stage `stage_name` installed `packages`, dropping the packages of
+    /// stages that were removed since.
+    fn record_stage_packages(&self, stage_name: &str, packages: &[String]) -> anyhow::Result<()> {
+        let mut stage_packages = self.stage_packages()?;
+        stage_packages.retain(|stage, _| self.directories["custom_stages"].join(stage).exists());
+        
+        let recorded = stage_packages.entry(stage_name.to_owned()).or_default();
+        for package in packages {
+            if !recorded.contains(package) {
+                recorded.push(package.clone());
+            }
+        }
+        
+        let stage_packages_file = self.directories["cache_directory"].join(&self.files["stage_packages_file"]);
+        fs::write(&stage_packages_file, serde_json::to_string_pretty(&stage_packages)?)
+            .map_err(|e| anyhow!("Failed to write \"{}\": {}", stage_packages_file.display(), e))
+    }
+    
+    /// Build the `ctx` table for the custom stage `stage_name` in `lua` and hand it to `f`.
+    ///
+    /// - `ctx.config`: the evaluated configuration, the same as `goat eval --json` shows it.
+    /// - `ctx.hostname`: the configured hostname.
+    /// - `ctx.directories`: goat's directories by name, like `ctx.directories.custom_stages`.
+    /// - `ctx.packages.install({ ... })`: install packages with the configured `install_strategy`
+    ///   (from the package cache with `--offline`), failing packages raise an error. The installed
+    ///   packages are recorded for the stage, so the "Packages" stage leaves them alone until the
+    ///   stage's file is removed. Stages applied in parallel take turns installing, see
+    ///   `Goat.package_lock`.
+    /// - `ctx.packages.installed()`: every installed package.
+    /// - `ctx.run(command)`: run a shell command and return its stdout and stderr, a command
+    ///   that fails raises an error with its output.
+    /// - `ctx.log.info(message)`, `ctx.log.warn(message)` and `ctx.log.error(message)`.
+    ///
+    /// With `read_only`, used for `stage.diff`, `ctx.packages.install` and `ctx.run` are left out
+    /// so a diff can't change the system.
+    ///
+    /// The functions in `ctx` borrow `self` so they only work until `f` returns.
+    pub fn with_stage_context<R>(&self,
+                                 lua: &GoatLua,
+                                 stage_name: &str,
+                                 read_only: bool,
+                                 f: impl FnOnce(mlua::Table) -> mlua::Result<R>) -> anyhow::Result<R> {
+        let config = serde_json::to_value(&self.config)?;
+
+        lua.lua.scope(|scope| {
+            let ctx = lua.lua.create_table()?;
+
+            ctx.set("config", json_to_lua(&lua.lua, &config)?)?;
+            ctx.set("hostname", self.config.hostname.as_str())?;
+
+            let directories = lua.lua.create_table()?;
+            for (name, directory) in &self.directories {
+                directories.set(name.as_str(), directory.to_string_lossy())?;
+            }
+            ctx.set("directories", directories)?;
+
+            let packages = lua.lua.create_table()?;
+            packages.set("installed", scope.create_function(|_, ()| {
+                self.package_manager.all_packages().map_err(mlua::Error::external)
+            })?)?;
+            ctx.set("packages", packages.clone())?;
+
+            let log = lua.lua.create_table()?;
+            log.set("info", scope.create_function(|_, message: String| {
+                log::info!("{}: {}", stage_name, message);
+                Ok(())
+            })?)?;
+            log.set("warn", scope.create_function(|_, message: String| {
+                log::warn!("{}: {}", stage_name, message);
+                Ok(())
+            })?)?;
+            log.set("error", scope.create_function(|_, message: String| {
+                log::error!("{}: {}", stage_name, message);
+                Ok(())
+            })?)?;
+            ctx.set("log", log)?;
+
+            if read_only {
+                return f(ctx);
+            }
+
+            packages.set("install", scope.create_function(|_, requested: Vec<String>| {
+                let packages: Vec<&str> = requested.iter().map(String::as_str).collect();
+                let _lock = self.package_lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+                let report = if self.offline {
+                    self.package_manager.install_offline(
+                        packages,
+                        &self.directories["package_cache_directory"],
+                        &self.config.install_strategy
+                    )
+                } else {
+                    self.package_manager.install(
+                        packages,
+                        self.config.build_user.as_deref(),
+                        &self.config.install_strategy
+                    )
+                }.map_err(mlua::Error::external)?;
+                
+                // Already installed packages are recorded too, the stage depends on them all the same.
+                let installed: Vec<String> = requested
+                    .into_iter()
+                    .filter(|package| !report.failed.iter().any(|(failed, _)| failed == package))
+                    .collect();
+                self.record_stage_packages(stage_name, &installed).map_err(mlua::Error::external)?;
+
+                if !report.failed.is_empty() {
+                    let failed: Vec<String> = report.failed.iter()
+                        .map(|(package, reason)| format!("\"{}\": {}", package, reason))
+                        .collect();
+                    return Err(mlua::Error::external(anyhow!("Failed to install {}", failed.join(", "))));
+                }
+
+                Ok(report.installed)
+            })?)?;
+
+            ctx.set("run", scope.create_function(|_, command: String| {
+                let output = command::output(&command).map_err(mlua::Error::external)?;
+
+                if !output.status.success() {
+                    return Err(mlua::Error::external(anyhow!(
+                        "\"{}\" failed with output: \n\n{}",
+                        command,
+                        String::from_utf8_lossy(&output.stderr)
+                    )));
+                }
+
+                Ok((
+                    String::from_utf8_lossy(&output.stdout).into_owned(),
+                    String::from_utf8_lossy(&output.stderr).into_owned()
+                ))
+            })?)?;
+
+            f(ctx)
+        }).map_err(|e| anyhow!("{}", e))
+    }
+}
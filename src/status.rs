@@ -0,0 +1,84 @@
+use serde::Serialize;
+use crate::goat::Goat;
+use crate::stage::StageDiff;
+// status.rs
+//
+// All logic related to the `status` subcommand should be placed here.
+
+/// Exit code used when the system matches the configuration.
+pub const EXIT_IN_SYNC: i32 = 0;
+/// Exit code used when at least one stage has drifted from the configuration.
+pub const EXIT_DRIFTED: i32 = 1;
+/// Exit code used when the drift could not be determined.
+pub const EXIT_ERROR: i32 = 2;
+
+/// The drift of a single stage.
+#[derive(Serialize)]
+pub struct StageStatus {
+    pub stage: String,
+    #[serde(flatten)]
+    pub diff: StageDiff
+}
+
+/// The drift of the whole system from the configuration file.
+#[derive(Serialize)]
+pub struct Status {
+    pub drifted: bool,
+    pub stages: Vec<StageStatus>
+}
+
+impl Status {
+    /// The exit code monitoring tools should receive for this status.
+    pub fn exit_code(&self) -> i32 {
+        if self.drifted { EXIT_DRIFTED } else { EXIT_IN_SYNC }
+    }
+
+    /// Print the status to stdout, either human readable or as JSON.
+    pub fn print(&self, json: bool) -> anyhow::Result<()> {
+        if json {
+            println!("{}", serde_json::to_string_pretty(self)?);
+            return Ok(())
+        }
+
+        for stage in &self.stages {
+            if stage.diff.is_empty() {
+                println!("Stage \"{}\": in sync", stage.stage);
+                continue;
+            }
+
+            println!("Stage \"{}\":", stage.stage);
+            for item in &stage.diff.missing {
+                println!("  + {} (missing)", item);
+            }
+            for item in &stage.diff.extra {
+                println!("  - {} (extra)", item);
+            }
+            for change in &stage.diff.changed {
+                println!("  ~ {}: \"{}\" -> \"{}\"", change.item, change.current, change.expected);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Goat {
+    /// Compare the running system against the configuration file without modifying anything.
+    ///
+    /// Unlike `sync` this doesn't require root privileges.
+    pub fn status(&self) -> anyhow::Result<Status> {
+        let mut stages = vec![];
+
+        for stage in self.stages()? {
+            stages.push(StageStatus {
+                stage: stage.name(),
+                diff: stage.diff(self)?
+            });
+        }
+
+        Ok(Status {
+            drifted: stages.iter().any(|stage| !stage.diff.is_empty()),
+            stages
+        })
+    }
+}
@@ -1,16 +1,53 @@
 use std::fs::DirEntry;
-use std::path::PathBuf;
+use std::time::Instant;
 use anyhow::anyhow;
 use nix::unistd::Uid;
 use goat_lua::GoatLua;
+use crate::events::{self, Event, OutputFormat};
 use crate::goat::Goat;
-use crate::stage::{CustomStage, Hostname, Packages, Stage, StageResult};
+use crate::history::{HistoryEntry, StageRecord};
+use crate::hooks::HookContext;
+use crate::order::{order_stages, parallel_groups};
+use crate::stage::{CustomStage, Hostname, Packages, Repositories, Stage, StageDiff, StageResult};
 use crate::stages;
 // sync.rs
 //
 // All logic related to the `-s` sync flag should be placed here.
 
+/// What applying a single stage came to, see `Goat::apply_stage`.
+struct AppliedStage {
+    /// What the stage was about to change (only with JSON output) and what it did.
+    result: anyhow::Result<(StageDiff, StageResult)>,
+
+    /// The stage applied but its `after` hooks failed.
+    hook_error: Option<anyhow::Error>,
+
+    duration_ms: u64
+}
+
 impl Goat {
+    /// Every stage a sync will go through in the order they are applied: built-in stages and the
+    /// custom stages found in the `custom_stages` directory, sorted by their dependencies.
+    /// Stages that don't depend on each other keep that order, custom stages by file name.
+    pub fn stages(&self) -> anyhow::Result<Vec<Box<dyn Stage>>> {
+        let mut stages = stages![
+            Hostname,
+            Repositories,
+            Packages
+        ];
+        
+        let mut custom_stages: Vec<DirEntry> = self.directories["custom_stages"].read_dir()?.collect::<Result<_, _>>()?;
+        // Leave out anything that isn't a stage, like the `.luarc.json` from `goat lsp-types`.
+        custom_stages.retain(|stage| stage.path().extension().is_some_and(|extension| extension == "lua"));
+        custom_stages.sort_by_key(|stage| stage.file_name());
+        
+        for stage in custom_stages {
+            stages.push(Box::new(CustomStage::new(stage.path())?));
+        }
+        
+        order_stages(stages)
+    }
+    
     /// This is where 99% of the magic happens.
     ///
     /// This function is what synchronizes the system to the current configuration file. The idea is
@@ -18,41 +55,287 @@ impl Goat {
     ///
     /// \*: The health check can create files and directories exclusive to `goat`'s requirements.
     pub fn sync(&self) -> anyhow::Result<()> {
+        // Building the stages runs the top level code of custom stages, which expects root.
+        Self::require_root()?;
+        
+        let stages = self.stages()?;
+        self.check_stage_hooks(&stages)?;
+        
+        self.apply_stages(stages)
+    }
+    
+    /// Same as `sync` but only the stages whose names are in `stage_names` are applied. Names that
+    /// aren't stages are an error.
+    pub fn sync_stages(&self, stage_names: &[String]) -> anyhow::Result<()> {
+        Self::require_root()?;
+        
+        let stages = self.stages()?;
+        self.check_stage_hooks(&stages)?;
+        
+        let names: Vec<String> = stages.iter().map(|stage| stage.name()).collect();
+        if let Some(unknown) = stage_names.iter().find(|name| !names.contains(name)) {
+            return Err(anyhow!("\"{}\" is not a stage, the stages are: {}", unknown, names.join(", ")));
+        }
+        
+        let stages = stages
+            .into_iter()
+            .filter(|stage| stage_names.contains(&stage.name()))
+            .collect();
+        
+        self.apply_stages(stages)
+    }
+    
+    fn require_root() -> anyhow::Result<()> {
         if !Uid::effective().is_root() {
             return Err(anyhow!("Sync requires root privileges!"));
         }
-
+        
+        Ok(())
+    }
+    
+    fn apply_stages(&self, stages: Vec<Box<dyn Stage>>) -> anyhow::Result<()> {
         // TODO: We don't want a halfway synced system so in the future we need to containerize our
         //       sync so if an error is thrown we cancel the build and have no side effects.
         
-        let mut stages = stages![
-            Hostname,
-            Packages
-        ];
-        
-        let custom_stages: Vec<DirEntry> = self.directories["custom_stages"].read_dir()?.collect::<Result<_, _>>()?;
+        let mut history = HistoryEntry::begin(self);
+        let result = self.run_stages(stages, &mut history);
         
-        for stage in custom_stages {
-            stages.push(Box::new(
-                CustomStage {
-                    path: PathBuf::from(stage.path()),
-                }
-            ));
-        }
-        
-        for stage in stages {
-            match stage.apply(self) {
-                Ok(StageResult::Done) => {
-                    log::warn!("Stage \"{}\" complete", stage.name())
-                },
-                // Let the user know a stage was skipped.
-                Ok(StageResult::Skipped) => {
-                    log::warn!("Skipped stage \"{}\" as it would have no effect.", stage.name())
-                },
-                Err(e) => return Err(e),
+        if !matches!(result, Ok(0)) {
+            let error = match &result {
+                Ok(failed) => format!("{} item(s) failed or were skipped", failed),
+                Err(e) => e.to_string()
+            };
+            
+            // The sync already failed, a failing hook shouldn't hide why.
+            if let Err(e) = self.run_hooks(&self.config.hooks.on_failure, HookContext {
+                hook: "on_failure",
+                result: Some("failed"),
+                error: Some(&error),
+                ..HookContext::default()
+            }) {
+                log::error!("on_failure hook failed: {}", e);
             }
         }
+        
+        history.finish(self, &result);
+        if let Err(e) = self.record_history(&history) {
+            log::error!("Failed to record the sync in the history: {}", e);
+        }
+        
+        let failed = result?;
+        if failed > 0 {
+            return Err(anyhow!("Sync finished but {} item(s) failed or were skipped, see above.", failed));
+        }
 
         Ok(())
     }
-}
\ No newline at end of file
+    
+    /// Apply every stage in order, recording each result in `history`. Returns how many items
+    /// failed or were skipped, an error means a stage failed as a whole and the sync stopped.
+    fn run_stages(&self, stages: Vec<Box<dyn Stage>>, history: &mut HistoryEntry) -> anyhow::Result<usize> {
+        let json = self.output == OutputFormat::Json;
+        let sync_start = Instant::now();
+        let mut failed = 0;
+        
+        if json {
+            events::emit(&Event::SyncStart {
+                stages: stages.iter().map(|stage| stage.name()).collect()
+            });
+        }
+        
+        if let Err(e) = self.run_hooks(&self.config.hooks.pre_sync, HookContext { hook: "pre_sync", ..HookContext::default() }) {
+            if json {
+                events::emit(&Event::SyncFinish {
+                    duration_ms: events::millis(sync_start.elapsed()),
+                    success: false,
+                    failed
+                });
+            }
+            
+            return Err(anyhow!("pre_sync hook failed, nothing was synced: {}", e));
+        }
+        
+        for group in parallel_groups(&stages) {
+            let results = match group {
+                [stage] => vec![self.apply_stage(stage.as_ref())],
+                _ => {
+                    log::info!("Applying {} at the same time",
+                        group.iter().map(|stage| format!("\"{}\"", stage.name())).collect::<Vec<_>>().join(", "));
+                    
+                    std::thread::scope(|scope| {
+                        let handles: Vec<_> = group.iter()
+                            .map(|stage| scope.spawn(move || self.apply_stage(stage.as_ref())))
+                            .collect();
+                        
+                        handles.into_iter()
+                            .map(|handle| handle.join().unwrap_or_else(|_| AppliedStage {
+                                result: Err(anyhow!("The stage panicked")),
+                                hook_error: None,
+                                duration_ms: 0
+                            }))
+                            .collect::<Vec<_>>()
+                    })
+                }
+            };
+            
+            let mut error = None;
+            
+            for (stage, AppliedStage { result, hook_error, duration_ms }) in group.iter().zip(results) {
+                let name = stage.name();
+                
+                let record = |result: &str| StageRecord {
+                    stage: name.clone(),
+                    result: result.to_owned(),
+                    error: None,
+                    report: None,
+                    hook_error: None
+                };
+                
+                match result {
+                    Ok((changes, StageResult::Done)) => {
+                        history.stages.push(record("done"));
+                        
+                        if json {
+                            events::emit(&Event::StageFinish { stage: &name, duration_ms, changes: &changes, report: None })
+                        } else {
+                            log::warn!("Stage \"{}\" complete", name)
+                        }
+                    },
+                    // Let the user know a stage was skipped.
+                    Ok((_, StageResult::Skipped)) => {
+                        history.stages.push(record("skipped"));
+                        
+                        if json {
+                            events::emit(&Event::StageSkip { stage: &name, duration_ms, reason: "it would have no effect" })
+                        } else {
+                            log::warn!("Skipped stage \"{}\" as it would have no effect.", name)
+                        }
+                    },
+                    // Partial failures don't stop the sync, they fail it once every stage ran.
+                    Ok((changes, StageResult::Report(report))) => {
+                        failed += report.failed.len() + report.skipped.len();
+                        
+                        if json {
+                            events::emit(&Event::StageFinish { stage: &name, duration_ms, changes: &changes, report: Some(&report) });
+                        } else {
+                            for entry in &report.skipped {
+                                log::warn!("Skipped \"{}\": {}", entry.item, entry.reason);
+                            }
+                            for entry in &report.failed {
+                                log::error!("Failed \"{}\": {}", entry.item, entry.reason);
+                            }
+                            
+                            log::warn!(
+                                "Stage \"{}\" complete: {} done, {} failed, {} skipped",
+                                name,
+                                report.done.len(),
+                                report.failed.len(),
+                                report.skipped.len()
+                            );
+                        }
+                        
+                        history.stages.push(StageRecord {
+                            report: Some(report),
+                            ..record("done")
+                        });
+                    },
+                    Err(e) => {
+                        history.stages.push(StageRecord {
+                            error: Some(e.to_string()),
+                            ..record("error")
+                        });
+                        
+                        if json {
+                            events::emit(&Event::StageError { stage: &name, duration_ms, error: e.to_string() });
+                        }
+                        
+                        // Stages of the same group already ran, record them before stopping.
+                        error.get_or_insert(e);
+                    },
+                }
+                
+                // The stage itself applied, so the sync goes on but fails once every stage ran.
+                if let Some(hook_error) = hook_error {
+                    log::error!("{}", hook_error);
+                    failed += 1;
+                    
+                    if let Some(record) = history.stages.last_mut() {
+                        record.hook_error = Some(hook_error.to_string());
+                    }
+                }
+            }
+
+            if let Some(e) = error {
+                if json {
+                    events::emit(&Event::SyncFinish {
+                        duration_ms: events::millis(sync_start.elapsed()),
+                        success: false,
+                        failed
+                    });
+                }
+                
+                return Err(e)
+            }
+        }
+        
+        let post_sync = self.run_hooks(&self.config.hooks.post_sync, HookContext {
+            hook: "post_sync",
+            result: Some(if failed == 0 { "done" } else { "failed" }),
+            ..HookContext::default()
+        });
+        
+        if json {
+            events::emit(&Event::SyncFinish {
+                duration_ms: events::millis(sync_start.elapsed()),
+                success: failed == 0 && post_sync.is_ok(),
+                failed
+            });
+        }
+        
+        post_sync.map_err(|e| anyhow!("post_sync hook failed: {}", e))?;
+        
+        Ok(failed)
+    }
+    
+    /// Run the `before` hooks, apply a single stage and run its `after` hooks. A failing `before`
+    /// hook fails the stage, a failing `after` hook is reported on its own since the stage already
+    /// applied.
+    fn apply_stage(&self, stage: &dyn Stage) -> AppliedStage {
+        let json = self.output == OutputFormat::Json;
+        let name = stage.name();
+        let stage_start = Instant::now();
+        
+        // What the stage is about to change, only worth the extra commands for the events. The
+        // diff is informational, failing to take it doesn't keep the stage from being applied.
+        let changes = if json {
+            events::emit(&Event::StageStart { stage: &name });
+            stage.diff(self).unwrap_or_else(|e| {
+                log::warn!("Failed to take the changes of stage \"{}\": {}", name, e);
+                StageDiff::default()
+            })
+        } else {
+            StageDiff::default()
+        };
+        
+        let result = self.run_stage_hooks("before", &name, None).and_then(|_| stage.apply(self));
+        
+        let hook_error = match &result {
+            Ok(result) => {
+                let outcome = match result {
+                    StageResult::Done => "done",
+                    StageResult::Skipped => "skipped",
+                    StageResult::Report(report) if report.failed.is_empty() && report.skipped.is_empty() => "done",
+                    StageResult::Report(_) => "failed"
+                };
+                self.run_stage_hooks("after", &name, Some(outcome)).err()
+            },
+            Err(_) => None
+        };
+        
+        AppliedStage {
+            result: result.map(|result| (changes, result)),
+            hook_error,
+            duration_ms: events::millis(stage_start.elapsed())
+        }
+    }
+}
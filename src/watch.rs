@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+use crate::goat::Goat;
+use crate::status::EXIT_ERROR;
+// watch.rs
+//
+// All logic related to the `watch` subcommand and its systemd units should be placed here.
+
+/// How often the configuration directory is polled for inotify events.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Name shared by the generated systemd service and timer.
+const UNIT_NAME: &str = "goat-drift";
+
+/// Watch `directory` and every directory below it, inotify watches aren't recursive. `.git` is left
+/// out, git touches it all the time without changing the configuration.
+fn watch_directory(inotify: &Inotify,
+                   directory: &Path,
+                   watches: &mut HashMap<WatchDescriptor, PathBuf>) -> anyhow::Result<()> {
+    let watch = inotify.add_watch(
+        directory,
+        AddWatchFlags::IN_CLOSE_WRITE
+            | AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_MOVED_FROM
+            | AddWatchFlags::IN_MOVED_TO
+    )?;
+    watches.insert(watch, directory.to_path_buf());
+
+    for entry in fs::read_dir(directory)?.flatten() {
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) && entry.file_name() != ".git" {
+            watch_directory(inotify, &entry.path(), watches)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Start watching directories that were created or moved in with `events`, and forget the
+/// watches the kernel removed along with their directory.
+fn update_watches(inotify: &Inotify,
+                  events: &[InotifyEvent],
+                  watches: &mut HashMap<WatchDescriptor, PathBuf>) {
+    for event in events {
+        if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+            watches.remove(&event.wd);
+            continue;
+        }
+
+        if !event.mask.contains(AddWatchFlags::IN_ISDIR)
+            || !event.mask.intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO) {
+            continue;
+        }
+
+        let (Some(parent), Some(name)) = (watches.get(&event.wd), &event.name) else {
+            continue;
+        };
+
+        if name == ".git" {
+            continue;
+        }
+
+        // The directory can be gone again by now.
+        let directory = parent.join(name);
+        if let Err(e) = watch_directory(inotify, &directory, watches) {
+            log::warn!("Failed to watch \"{}\": {}", directory.display(), e);
+        }
+    }
+}
+
+pub struct WatchOptions {
+    /// Seconds between two drift checks.
+    pub interval: u64,
+
+    /// Stages that get synced automatically when they drift.
+    pub sync_stages: Vec<String>,
+
+    /// Run a single check and return instead of looping forever.
+    pub once: bool
+}
+
+impl Goat {
+    /// Re-evaluate the configuration and the system, log any drift and sync the stages selected in
+    /// `options`. Returns the exit code `goat status` would have returned.
+    ///
+    /// `last_report` holds the previous drift so unchanged drift isn't logged in full every check.
+    fn check_drift(options: &WatchOptions, last_report: &mut Option<String>) -> i32 {
+        let system = match Goat::load(false, &[]) {
+            Ok(system) => system,
+            Err(e) => {
+                log::error!("Failed to load configuration: {}", e);
+                return EXIT_ERROR;
+            }
+        };
+
+        let status = match system.status() {
+            Ok(status) => status,
+            Err(e) => {
+                log::error!("Failed to check for drift: {}", e);
+                return EXIT_ERROR;
+            }
+        };
+
+        // A misspelled stage would never be synced, custom stages can come and go between checks.
+        if let Some(unknown) = options.sync_stages.iter().find(|name| !status.stages.iter().any(|stage| stage.stage == **name)) {
+            log::error!("\"{}\" given with --sync-stage is not a stage", unknown);
+            return EXIT_ERROR;
+        }
+
+        if !status.drifted {
+            if last_report.take().is_some() {
+                log::info!("System is back in sync with the configuration.");
+            }
+            return status.exit_code();
+        }
+
+        let report = serde_json::to_string(&status).ok();
+        if *last_report == report && report.is_some() {
+            log::warn!("System is still drifted from the configuration.");
+        } else {
+            for stage in status.stages.iter().filter(|stage| !stage.diff.is_empty()) {
+                log::warn!(
+                    "Stage \"{}\" drifted: {} missing, {} extra, {} changed",
+                    stage.stage,
+                    stage.diff.missing.len(),
+                    stage.diff.extra.len(),
+                    stage.diff.changed.len()
+                );
+
+                if !stage.diff.missing.is_empty() {
+                    log::info!("Missing: {}", stage.diff.missing.join(", "));
+                }
+                if !stage.diff.extra.is_empty() {
+                    log::info!("Extra: {}", stage.diff.extra.join(", "));
+                }
+                for change in &stage.diff.changed {
+                    log::info!("Changed: {} \"{}\" -> \"{}\"", change.item, change.current, change.expected);
+                }
+            }
+        }
+        *last_report = report;
+
+        let stages_to_sync: Vec<String> = status.stages
+            .iter()
+            .filter(|stage| !stage.diff.is_empty() && options.sync_stages.contains(&stage.stage))
+            .map(|stage| stage.stage.clone())
+            .collect();
+
+        if !stages_to_sync.is_empty() {
+            log::info!("Syncing drifted stage(s): {}", stages_to_sync.join(", "));
+            if let Err(e) = system.sync_stages(&stages_to_sync) {
+                log::error!("Failed to sync drifted stages: {}", e);
+            }
+        }
+
+        status.exit_code()
+    }
+
+    /// Check the system for drift every `options.interval` seconds or whenever something in the
+    /// configuration directory (or a directory below it, like `modules/`) changes.
+    ///
+    /// This only returns early when `options.once` is set, in which case the exit code of the single
+    /// check is returned.
+    pub fn watch(options: &WatchOptions) -> anyhow::Result<i32> {
+        let mut last_report = None;
+
+        if options.once {
+            return Ok(Self::check_drift(options, &mut last_report));
+        }
+
+        let directories = Self::get_directories();
+        let configuration_directory = &directories["configuration_directory"];
+
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK)?;
+        let mut watches = HashMap::new();
+        watch_directory(&inotify, configuration_directory, &mut watches)?;
+
+        log::info!(
+            "Watching \"{}\" and checking for drift every {} second(s)...",
+            configuration_directory.display(),
+            options.interval
+        );
+
+        let interval = Duration::from_secs(options.interval);
+
+        loop {
+            Self::check_drift(options, &mut last_report);
+            let last_check = Instant::now();
+
+            while last_check.elapsed() < interval {
+                thread::sleep(POLL_INTERVAL);
+
+                match inotify.read_events() {
+                    Ok(mut events) if !events.is_empty() => {
+                        // Editors tend to produce a burst of events per save, let them settle
+                        // and take the rest along so we only check once.
+                        thread::sleep(POLL_INTERVAL);
+                        if let Ok(more_events) = inotify.read_events() {
+                            events.extend(more_events);
+                        }
+                        update_watches(&inotify, &events, &mut watches);
+
+                        log::info!("Configuration directory changed, re-evaluating...");
+                        break;
+                    }
+                    Ok(_) | Err(Errno::EAGAIN) => {},
+                    Err(e) => return Err(e.into())
+                }
+            }
+        }
+    }
+
+    /// Render a systemd service and timer pair that runs `goat watch --once` every `interval`
+    /// seconds. Returned as a list of `(file name, contents)`.
+    pub fn systemd_units(interval: u64, sync_stages: &[String]) -> anyhow::Result<Vec<(String, String)>> {
+        let mut exec_start = format!("{} watch --once", std::env::current_exe()?.display());
+        for stage in sync_stages {
+            exec_start.push_str(&format!(" --sync-stage \"{}\"", stage));
+        }
+
+        let service = format!(
+            "[Unit]\n\
+             Description=Check the system for drift from the goat configuration\n\
+             After=network-online.target\n\
+             \n\
+             [Service]\n\
+             Type=oneshot\n\
+             ExecStart={}\n",
+            exec_start
+        );
+
+        let timer = format!(
+            "[Unit]\n\
+             Description=Periodically check the system for drift from the goat configuration\n\
+             \n\
+             [Timer]\n\
+             OnBootSec=5min\n\
+             OnUnitActiveSec={}s\n\
+             \n\
+             [Install]\n\
+             WantedBy=timers.target\n",
+            interval
+        );
+
+        Ok(vec![
+            (format!("{}.service", UNIT_NAME), service),
+            (format!("{}.timer", UNIT_NAME), timer)
+        ])
+    }
+
+    /// Write the units from `systemd_units` into `directory`.
+    pub fn install_systemd_units(directory: &Path, interval: u64, sync_stages: &[String]) -> anyhow::Result<()> {
+        fs::create_dir_all(directory)?;
+
+        for (file_name, contents) in Self::systemd_units(interval, sync_stages)? {
+            let path = directory.join(file_name);
+            fs::write(&path, contents)?;
+            log::info!("Wrote \"{}\"", path.display());
+        }
+
+        log::info!("Enable the drift check with `systemctl daemon-reload && systemctl enable --now {}.timer`", UNIT_NAME);
+
+        Ok(())
+    }
+}